@@ -1,8 +1,127 @@
-use std::process::Command;
+use chrono::SecondsFormat;
+use serde_json::json;
+use std::io::Read;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
-use crate::config::Config;
+use std::time::{Duration, Instant};
+use crate::audit;
+use crate::config::{ActionConfig, ActionKind, Config};
+use crate::luks;
+use crate::policy::{self, Profile};
+use crate::secret;
 use crate::triggers::network::NetworkListener;
+use crate::triggers::TriggerEvent;
+use crate::veracrypt;
+
+/// Reported by the executor while it runs, in this order: `Triggered` with
+/// the profile it runs, one `Step`/`Failed` per action that ran, then `Done`
+/// once the pipeline ended.
+#[derive(Debug, Clone)]
+pub enum ActionProgress {
+    Triggered(TriggerEvent, Profile),
+    Step(String),
+    Failed(String),
+    Done,
+}
+
+pub type ProgressSender = mpsc::Sender<ActionProgress>;
+pub type ProgressReceiver = mpsc::Receiver<ActionProgress>;
+
+/// The main thread's view of an activation: the event that fired, the
+/// profile being run and the progress received so far. Shared with the alert
+/// window.
+pub struct Activation {
+    pub event: TriggerEvent,
+    pub profile: Profile,
+    pub steps: Vec<ActionProgress>,
+    /// `Some(true)` after `Done`, `Some(false)` if the executor went away first
+    pub finished: Option<bool>,
+    progress: ProgressReceiver,
+}
+
+impl Activation {
+    /// Blocks until a trigger fires. `None` if every sender is gone first.
+    pub fn wait_for_trigger(progress: ProgressReceiver) -> Option<Self> {
+        loop {
+            if let ActionProgress::Triggered(event, profile) = progress.recv().ok()? {
+                return Some(Self { event, profile, steps: Vec::new(), finished: None, progress });
+            }
+        }
+    }
+
+    /// Takes in whatever progress has arrived, without blocking.
+    pub fn poll(&mut self) {
+        while self.finished.is_none() {
+            match self.progress.try_recv() {
+                Ok(p) => self.record(p),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => self.finished = Some(false),
+            }
+        }
+    }
+
+    /// Blocks until the executor is done. Returns whether it completed.
+    pub fn wait(&mut self) -> bool {
+        while self.finished.is_none() {
+            match self.progress.recv() {
+                Ok(p) => self.record(p),
+                Err(_) => self.finished = Some(false),
+            }
+        }
+        self.finished == Some(true)
+    }
+
+    /// After `wait`, blocks until the next trigger fires and starts over with
+    /// it. `false` once every sender is gone.
+    pub fn next_trigger(&mut self) -> bool {
+        while let Ok(p) = self.progress.recv() {
+            if let ActionProgress::Triggered(event, profile) = p {
+                self.event = event;
+                self.profile = profile;
+                self.steps.clear();
+                self.finished = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn record(&mut self, progress: ActionProgress) {
+        match progress {
+            ActionProgress::Done => self.finished = Some(true),
+            ActionProgress::Triggered(..) => {}
+            step => self.steps.push(step),
+        }
+    }
+}
+
+/// Bytes of a command's stdout or stderr kept for the log.
+const OUTPUT_LIMIT: u64 = 64 * 1024;
+
+/// How a command ended and what it printed.
+struct Finished {
+    /// `None` if it timed out and was killed
+    status: Option<ExitStatus>,
+    timeout: Duration,
+    stdout: String,
+    stderr: String,
+}
+
+impl Finished {
+    /// Failures carry the first line of stderr, if any.
+    fn result(&self) -> std::result::Result<(), String> {
+        let status = match self.status {
+            Some(status) if status.success() => return Ok(()),
+            Some(status) => status.to_string(),
+            None => format!("timed out after {}s, killed", self.timeout.as_secs()),
+        };
+        match self.stderr.lines().map(str::trim).find(|l| !l.is_empty()) {
+            Some(line) => Err(format!("{}: {}", status, line)),
+            None => Err(status),
+        }
+    }
+}
 
 pub struct ActionExecutor {
     config: Config,
@@ -13,86 +132,403 @@ impl ActionExecutor {
         Self { config }
     }
 
-    pub fn execute(&self) {
-        log::warn!("[!] Dead Man Switch ACTIVATED");
-        
-        // Broadcast to network
-        if let Err(e) = NetworkListener::send_trigger_broadcast(&self.config) {
-            log::error!("Broadcast failed: {}", e);
+    /// Runs the profile the policy selects for the event's source, one step
+    /// at a time on a separate thread. Under `--dry-run` the event is marked
+    /// as a test; for test events commands are only logged. The thread
+    /// returns whether every step completed.
+    pub fn execute(&self, event: &TriggerEvent, progress: ProgressSender) -> thread::JoinHandle<bool> {
+        let mut event = event.clone();
+        event.test |= self.config.dry_run;
+
+        let profile = policy::select(&self.config, event.source);
+        log::warn!(trigger:% = event.source; "[!] Dead Man Switch ACTIVATED: {}", event);
+        log::warn!(profile = profile.name.as_str(); "[!] Running profile '{}': {}", profile.name,
+                   profile.actions.iter().map(ActionConfig::name).collect::<Vec<_>>().join(", "));
+        // On disk before anything runs
+        audit::record_sync("activation", json!({
+            "event": audit::event_data(&event),
+            "profile": profile.name,
+            "actions": profile.actions.iter().map(ActionConfig::name).collect::<Vec<_>>(),
+        }));
+        let _ = progress.send(ActionProgress::Triggered(event.clone(), profile.clone()));
+
+        let config = self.config.clone();
+        thread::spawn(move || {
+            let total = profile.actions.len();
+            let mut completed = true;
+            for (i, action) in profile.actions.iter().enumerate() {
+                if action.delay > 0 {
+                    thread::sleep(Duration::from_secs(action.delay));
+                }
+                log::info!("[+] Step {}/{}: {} (timeout {}s)", i + 1, total, action.name(), action.timeout().as_secs());
+                // Recorded first: the shutdown's result never is
+                audit::record_sync("action_start", json!({ "action": action.name(), "step": i + 1 }));
+                let result = Self::run(&config, &event, action);
+                let failed = matches!(result, ActionProgress::Failed(_));
+                if let ActionProgress::Step(msg) | ActionProgress::Failed(msg) = &result {
+                    audit::record_sync("action", json!({ "action": action.name(), "ok": !failed, "message": msg }));
+                }
+                let _ = progress.send(result);
+
+                if failed && !action.continue_on_error {
+                    log::error!("[!] {} failed, skipping the remaining {} step(s)", action.name(), total - i - 1);
+                    completed = false;
+                    break;
+                }
+            }
+            audit::record_sync("done", json!({ "completed": completed }));
+            let _ = progress.send(ActionProgress::Done);
+            completed
+        })
+    }
+
+    fn run(config: &Config, event: &TriggerEvent, action: &ActionConfig) -> ActionProgress {
+        if event.test {
+            if let Some(commands) = Self::commands(action) {
+                return Self::dry_run(&commands);
+            }
+        }
+
+        let timeout = action.timeout();
+        match action.kind {
+            ActionKind::Broadcast => Self::broadcast(config, event),
+            ActionKind::LockScreen => Self::lock_screen(timeout),
+            ActionKind::DismountVeracrypt => Self::dismount_veracrypt(action, timeout),
+            ActionKind::Shutdown => Self::force_shutdown(timeout),
+            ActionKind::Exec => Self::exec(action, event, timeout),
+            ActionKind::CloseLuks => Self::close_luks(action, timeout),
         }
+    }
+
+    /// The commands (program and arguments) an action would run now; `None`
+    /// for the broadcast, which is sent (marked as a test) even in a dry run.
+    fn commands(action: &ActionConfig) -> Option<Vec<Vec<String>>> {
+        let (program, args) = match action.kind {
+            ActionKind::Broadcast => return None,
+            ActionKind::LockScreen => Self::lock_command(),
+            ActionKind::DismountVeracrypt => {
+                return Some(veracrypt::dismount_commands(veracrypt::program(action), &action.volumes));
+            }
+            ActionKind::Shutdown => Self::shutdown_command(),
+            ActionKind::Exec => {
+                let program = action.program.clone().unwrap_or_default();
+                return Some(vec![std::iter::once(program).chain(action.args.iter().cloned()).collect()]);
+            }
+            ActionKind::CloseLuks => {
+                return Some(luks::live_plan(&action.mappings).steps.iter().map(luks::Step::command).collect());
+            }
+        };
+        Some(vec![std::iter::once(program).chain(args.iter().copied()).map(String::from).collect()])
+    }
+
+    /// Runs a command to completion, killing it once `timeout` has passed.
+    /// A non-zero exit counts as a failure.
+    pub(crate) fn run_command(program: &str, args: &[&str], timeout: Duration) -> std::result::Result<(), String> {
+        Self::command_output(program, args, timeout).map(|_| ())
+    }
+
+    /// `run_command`, returning what the command printed on stdout.
+    pub(crate) fn command_output(program: &str, args: &[&str], timeout: Duration) -> std::result::Result<String, String> {
+        let mut command = Command::new(program);
+        command.args(args);
+        let finished = Self::spawn_and_wait(command, timeout).map_err(|e| e.to_string())?;
+        finished.result()?;
+        Ok(finished.stdout)
+    }
+
+    /// Runs `command` with its output captured. On timeout the command (and
+    /// on Unix its process group) is killed; the output up to then is kept.
+    fn spawn_and_wait(mut command: Command, timeout: Duration) -> std::io::Result<Finished> {
+        secret::scrub_env(&mut command).stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        let mut child = command.spawn()?;
+
+        // Drained on the side so a chatty command cannot fill a pipe and stall
+        let stdout = Self::drain(child.stdout.take());
+        let stderr = Self::drain(child.stderr.take());
 
-        // Spawn async dismount/shutdown
-        thread::spawn(|| {
-            thread::sleep(Duration::from_secs(3));
-            Self::dismount_veracrypt();
-            Self::force_shutdown();
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            match child.try_wait()? {
+                Some(status) => break Some(status),
+                None if Instant::now() >= deadline => {
+                    // Helpers the command started would otherwise live on
+                    #[cfg(unix)]
+                    unsafe {
+                        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                    }
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    break None;
+                }
+                None => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+
+        // A detached grandchild may hold a pipe open; don't wait on it long
+        let collect = |rx: mpsc::Receiver<String>| rx.recv_timeout(Duration::from_secs(1)).unwrap_or_default();
+        Ok(Finished { status, timeout, stdout: collect(stdout), stderr: collect(stderr) })
+    }
+
+    /// Reads a pipe to the end on its own thread, keeping the first
+    /// `OUTPUT_LIMIT` bytes.
+    fn drain(pipe: Option<impl Read + Send + 'static>) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.by_ref().take(OUTPUT_LIMIT).read_to_end(&mut output);
+                let _ = std::io::copy(&mut pipe, &mut std::io::sink());
+            }
+            let _ = tx.send(String::from_utf8_lossy(&output).into_owned());
         });
+        rx
     }
 
-    fn veracrypt_path() -> &'static str {
+    fn dry_run(commands: &[Vec<String>]) -> ActionProgress {
+        let lines: Vec<String> = commands.iter()
+            .map(|command| command.iter()
+                .map(|arg| if arg.contains(' ') { format!("\"{}\"", arg) } else { arg.clone() })
+                .collect::<Vec<_>>()
+                .join(" "))
+            .collect();
+        if lines.is_empty() {
+            log::warn!("[!] Dry run, nothing to run");
+            return ActionProgress::Step("Would run nothing".into());
+        }
+        for line in &lines {
+            log::warn!("[!] Dry run, would run: {}", line);
+        }
+        ActionProgress::Step(format!("Would run: {}", lines.join("; ")))
+    }
+
+    fn broadcast(config: &Config, event: &TriggerEvent) -> ActionProgress {
+        match NetworkListener::send_trigger_broadcast(config, event) {
+            Ok(()) => ActionProgress::Step("Trigger broadcast sent".into()),
+            Err(e) => {
+                log::error!("Broadcast failed: {}", e);
+                ActionProgress::Failed(format!("Broadcast failed: {}", e))
+            }
+        }
+    }
+
+    pub(crate) fn lock_command() -> (&'static str, &'static [&'static str]) {
         if cfg!(windows) {
-            "C:\\Program Files\\VeraCrypt\\VeraCrypt.exe"
+            ("rundll32.exe", &["user32.dll,LockWorkStation"])
         } else if cfg!(target_os = "macos") {
-            "/Applications/VeraCrypt.app/Contents/MacOS/VeraCrypt"
+            ("pmset", &["displaysleepnow"])
         } else {
-            "veracrypt"
+            ("loginctl", &["lock-sessions"])
         }
     }
 
-    fn dismount_veracrypt() {
-        let args = if cfg!(windows) {
-            vec!["/d", "/f", "/w", "/q", "/s"]
-        } else {
-            vec!["-d", "-f"]
-        };
+    fn lock_screen(timeout: Duration) -> ActionProgress {
+        let (program, args) = Self::lock_command();
 
-        match Command::new(Self::veracrypt_path()).args(&args).output() {
-            Ok(_) => log::info!("[+] VeraCrypt dismounted"),
-            Err(e) => log::error!("VeraCrypt error: {}", e),
+        match Self::run_command(program, args, timeout) {
+            Ok(()) => {
+                log::info!("[+] Screen locked");
+                ActionProgress::Step("Screen locked".into())
+            }
+            Err(e) => {
+                log::error!("Lock error: {}", e);
+                ActionProgress::Failed(format!("Lock error: {}", e))
+            }
         }
     }
 
-    fn force_shutdown() {
-        let result = if cfg!(windows) {
-            Command::new("shutdown").args(&["/p", "/f"]).output()
+    fn dismount_veracrypt(action: &ActionConfig, timeout: Duration) -> ActionProgress {
+        let retries = action.retries.unwrap_or(veracrypt::DEFAULT_RETRIES);
+        match veracrypt::dismount(veracrypt::program(action), &action.volumes, retries, timeout) {
+            Ok(summary) => {
+                log::info!("[+] {}", summary);
+                ActionProgress::Step(summary)
+            }
+            Err(e) => {
+                log::error!("VeraCrypt error: {}", e);
+                ActionProgress::Failed(format!("VeraCrypt error: {}", e))
+            }
+        }
+    }
+
+    pub(crate) fn shutdown_command() -> (&'static str, &'static [&'static str]) {
+        if cfg!(windows) {
+            ("shutdown", &["/p", "/f"])
         } else if cfg!(target_os = "macos") {
-            Command::new("halt").arg("-q").output()
+            ("halt", &["-q"])
         } else {
-            Command::new("systemctl").args(&["poweroff", "-f"]).output()
+            ("systemctl", &["poweroff", "-f"])
+        }
+    }
+
+    fn force_shutdown(timeout: Duration) -> ActionProgress {
+        let (program, args) = Self::shutdown_command();
+
+        match Self::run_command(program, args, timeout) {
+            Ok(()) => {
+                log::info!("[+] System shutdown initiated");
+                ActionProgress::Step("System shutdown initiated".into())
+            }
+            Err(e) => {
+                log::error!("Shutdown error: {}", e);
+                ActionProgress::Failed(format!("Shutdown error: {}", e))
+            }
+        }
+    }
+
+    fn close_luks(action: &ActionConfig, timeout: Duration) -> ActionProgress {
+        match luks::close(&action.mappings, timeout) {
+            Ok(closed) if closed.is_empty() => {
+                log::info!("[+] No LUKS mappings to close");
+                ActionProgress::Step("No LUKS mappings open".into())
+            }
+            Ok(closed) => {
+                log::info!("[+] LUKS mappings closed: {}", closed.join(", "));
+                ActionProgress::Step(format!("LUKS mappings closed: {}", closed.join(", ")))
+            }
+            Err(e) => {
+                log::error!("LUKS error: {}", e);
+                ActionProgress::Failed(format!("LUKS error: {}", e))
+            }
+        }
+    }
+
+    /// Runs a site-specific program with the event in its environment and
+    /// logs everything it prints.
+    fn exec(action: &ActionConfig, event: &TriggerEvent, timeout: Duration) -> ActionProgress {
+        let program = action.program.as_deref().unwrap_or_default();
+        let mut command = Command::new(program);
+        command.args(&action.args).envs(Self::event_env(event));
+        if let Some(dir) = &action.working_dir {
+            command.current_dir(dir);
+        }
+
+        let finished = match Self::spawn_and_wait(command, timeout) {
+            Ok(finished) => finished,
+            Err(e) => {
+                log::error!(action = "exec"; "Exec error: {}: {}", program, e);
+                return ActionProgress::Failed(format!("{}: {}", program, e));
+            }
         };
+        for line in finished.stdout.lines() {
+            log::info!(action = "exec"; "[+] {}: {}", program, line);
+        }
+        for line in finished.stderr.lines() {
+            log::warn!(action = "exec"; "[!] {} (stderr): {}", program, line);
+        }
 
-        match result {
-            Ok(_) => log::info!("[+] System shutdown initiated"),
-            Err(e) => log::error!("Shutdown error: {}", e),
+        match finished.result() {
+            Ok(()) => {
+                log::info!(action = "exec"; "[+] {} exited successfully", program);
+                ActionProgress::Step(format!("{} completed", program))
+            }
+            Err(e) => {
+                log::error!(action = "exec"; "Exec error: {}: {}", program, e);
+                ActionProgress::Failed(format!("{}: {}", program, e))
+            }
         }
     }
 
-    pub fn send_notification(modes: &[&str]) {
-        let msg = format!("DMS armed ({})", modes.join(", "));
-        
+    /// `DMS_TRIGGER_SOURCE`, `DMS_TRIGGER_TIME`, `DMS_HOSTNAME`, `DMS_TEST`
+    /// and `DMS_DETAIL_<KEY>` per event detail.
+    fn event_env(event: &TriggerEvent) -> Vec<(String, String)> {
+        let mut env = vec![
+            ("DMS_TRIGGER_SOURCE".to_string(), event.source.to_string()),
+            ("DMS_TRIGGER_TIME".to_string(), event.time.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            ("DMS_HOSTNAME".to_string(), event.hostname.clone()),
+            ("DMS_TEST".to_string(), if event.test { "1" } else { "0" }.to_string()),
+        ];
+        for (key, value) in &event.details {
+            let key: String = key.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                .collect();
+            env.push((format!("DMS_DETAIL_{}", key), value.clone()));
+        }
+        env
+    }
+
+    /// "DMS armed (...)", or the arming countdown while `arming_in` is set,
+    /// with the triggers that are not up (`problems`) on a second line.
+    /// Successive calls replace each other where the notification daemon
+    /// supports it.
+    pub fn send_notification(triggers: &[String], problems: &str, arming_in: Option<u64>) {
+        let mut msg = match arming_in {
+            Some(secs) => format!("DMS arming in {}s ({})", secs, triggers.join(", ")),
+            None => format!("DMS armed ({})", triggers.join(", ")),
+        };
+        if !problems.is_empty() {
+            msg.push('\n');
+            msg.push_str(problems);
+        }
+        Self::show_notification(&msg, Some("dms-arming"));
+    }
+
+    /// Shows a desktop notification.
+    pub fn notify(msg: &str) {
+        Self::show_notification(msg, None);
+    }
+
+    /// On a thread of its own: callers are on the async runtime, and
+    /// notify-send can take a while without a notification daemon.
+    fn show_notification(msg: &str, tag: Option<&str>) {
+        let (msg, tag) = (msg.to_string(), tag.map(String::from));
+        thread::spawn(move || Self::show_notification_now(&msg, tag.as_deref()));
+    }
+
+    fn show_notification_now(msg: &str, tag: Option<&str>) {
         #[cfg(target_os = "linux")]
         {
-            let _ = Command::new("notify-send")
+            let mut cmd = Command::new("notify-send");
+            secret::scrub_env(&mut cmd);
+            if let Some(tag) = tag {
+                cmd.arg("-h").arg(format!("string:x-canonical-private-synchronous:{}", tag))
+                    .arg("-h").arg(format!("string:x-dunst-stack-tag:{}", tag));
+            }
+            let _ = cmd
                 .env("DISPLAY", ":0.0")
-                .args(&["Dead Man Switch 🏴‍☠️", &msg])
+                .args(["Dead Man Switch 🏴‍☠️", msg])
                 .output();
         }
+
+        #[cfg(not(target_os = "linux"))]
+        let _ = tag;
         
         #[cfg(target_os = "windows")]
         {
             use notify_rust::Notification;
             let _ = Notification::new()
                 .summary("Dead Man Switch 🏴‍☠️")
-                .body(&msg)
+                .body(msg)
                 .show();
         }
         
         #[cfg(target_os = "macos")]
         {
-            let _ = Command::new("osascript")
+            let _ = secret::scrub_env(&mut Command::new("osascript"))
                 .arg("-e")
                 .arg(&format!("display notification \"{}\" with title \"DMS\"", msg))
                 .output();
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::triggers::TriggerSource;
+
+    #[test]
+    fn exec_env_carries_the_event() {
+        let event = TriggerEvent::new(TriggerSource::Network).with("peer", "192.0.2.7:45371");
+        let env = ActionExecutor::event_env(&event);
+        let get = |name: &str| env.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+        assert_eq!(get("DMS_TRIGGER_SOURCE"), Some("network"));
+        assert_eq!(get("DMS_DETAIL_PEER"), Some("192.0.2.7:45371"));
+        assert_eq!(get("DMS_TEST"), Some("0"));
+        assert_eq!(env.len(), 5);
+    }
+}
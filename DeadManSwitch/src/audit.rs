@@ -0,0 +1,574 @@
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::AuditConfig;
+use crate::error::{DmsError, Result};
+use crate::triggers::{hostname, TriggerEvent};
+
+/// `prev` of the first record.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The checkpoint is rewritten after this many records, or once this long
+/// has passed, rather than after every record: a peer that can make us log
+/// (e.g. with UDP packets) must not be able to make us rewrite it at will.
+const CHECKPOINT_EVERY: u64 = 100;
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+static LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+
+/// The open log: where the next record goes and what it chains to.
+struct AuditLog {
+    file: File,
+    path: PathBuf,
+    seq: u64,
+    hash: String,
+    /// Records appended since the checkpoint was last written
+    pending: u64,
+    checkpointed: Instant,
+}
+
+/// `/var/log/dms/audit.jsonl` for root, else under `$XDG_STATE_HOME`
+/// (`~/.local/state`).
+pub fn default_path() -> PathBuf {
+    #[cfg(unix)]
+    if crate::control::euid() == 0 {
+        return PathBuf::from("/var/log/dms/audit.jsonl");
+    }
+
+    std::env::var_os("XDG_STATE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local").join("state")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("dms")
+        .join("audit.jsonl")
+}
+
+/// Opens the log and continues its chain. Does nothing if auditing is
+/// disabled or a log is already open (a reload does not move it).
+pub fn open(config: &AuditConfig) -> Result<()> {
+    let mut guard = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    if !config.enabled || guard.is_some() {
+        return Ok(());
+    }
+
+    let path = config.path();
+    let (log, mismatch) = AuditLog::open(&path)?;
+    log::info!("[+] Audit log: {}", path.display());
+    *guard = Some(log);
+    drop(guard);
+
+    record_sync("start", json!({
+        "pid": std::process::id(),
+        "host": hostname(),
+        "version": env!("CARGO_PKG_VERSION"),
+    }));
+    if let Some((head, seq)) = mismatch {
+        log::error!("[!] Audit log does not end at its checkpoint (seq {}); it may have been truncated", head["seq"]);
+        record_sync("checkpoint_mismatch", json!({ "checkpoint": head, "next_seq": seq }));
+    }
+    Ok(())
+}
+
+/// Appends a record. A no-op until `open` succeeded.
+pub fn record(kind: &str, data: Value) {
+    append(kind, data, false);
+}
+
+/// Appends a record and fsyncs it, e.g. before actions run. Also writes the
+/// checkpoint.
+pub fn record_sync(kind: &str, data: Value) {
+    append(kind, data, true);
+}
+
+/// Writes the checkpoint for the records appended since the last one, e.g.
+/// before exiting.
+pub fn flush() {
+    let mut guard = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(log) = guard.as_mut() {
+        if let Err(e) = log.checkpoint() {
+            log::error!("[!] Audit checkpoint write failed: {}", e);
+        }
+    }
+}
+
+/// The audit form of a trigger event.
+pub fn event_data(event: &TriggerEvent) -> Value {
+    json!({
+        "source": event.source.to_string(),
+        "time": event.time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "host": event.hostname,
+        "details": event.details,
+        "test": event.test,
+    })
+}
+
+fn append(kind: &str, data: Value, sync: bool) {
+    let mut guard = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(log) = guard.as_mut() {
+        if let Err(e) = log.append(kind, data, sync) {
+            log::error!("[!] Audit log write failed: {}", e);
+        }
+    }
+}
+
+impl AuditLog {
+    /// Opens `path` for appending and continues its chain. Also returns the
+    /// checkpoint and the next seq if the log no longer reaches the
+    /// checkpoint, i.e. records were cut off the end.
+    fn open(path: &Path) -> Result<(Self, Option<(Value, u64)>)> {
+        let checkpoint = std::fs::read_to_string(checkpoint_path(path)).ok()
+            .and_then(|head| serde_json::from_str::<Value>(&head).ok());
+        let at = checkpoint.as_ref().and_then(|head| head["seq"].as_u64());
+        let tail = last_record(path, at)?;
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty() && !d.exists()) {
+            std::fs::create_dir_all(dir)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+            }
+        }
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let io = |e: std::io::Error| DmsError::Audit(format!("{}: {}", path.display(), e));
+        let mut file = options.open(path).map_err(io)?;
+
+        // Restarting must not paper over records cut off the end. Records
+        // after the checkpoint are expected: it is only written in batches.
+        let mismatch = checkpoint
+            .filter(|head| tail.at_hash.is_none() || head["hash"].as_str() != tail.at_hash.as_deref())
+            .map(|head| (head, tail.seq));
+
+        // A record half written when the power went (often by our own
+        // shutdown) must not end auditing: the chain goes on from the last
+        // good record, and says what it skipped
+        if tail.unterminated {
+            file.write_all(b"\n").map_err(io)?;
+        }
+        let mut log = Self {
+            file,
+            path: path.to_path_buf(),
+            seq: tail.seq,
+            hash: tail.hash,
+            pending: 0,
+            checkpointed: Instant::now(),
+        };
+        if tail.damaged > 0 {
+            log::error!("[!] Audit log ends in {} damaged line(s), continuing after seq {}",
+                        tail.damaged, tail.seq as i64 - 1);
+            log.append("damaged_tail", json!({ "lines": tail.damaged }), true).map_err(io)?;
+        }
+        Ok((log, mismatch))
+    }
+
+    fn append(&mut self, kind: &str, data: Value, sync: bool) -> std::io::Result<()> {
+        let mut record = json!({
+            "seq": self.seq,
+            "time": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "kind": kind,
+            "data": data,
+            "prev": self.hash,
+        });
+        let hash = digest(&record);
+        record["hash"] = Value::String(hash.clone());
+
+        let mut line = record.to_string();
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.seq += 1;
+        self.hash = hash;
+        self.pending += 1;
+        if sync {
+            self.file.sync_data()?;
+        }
+
+        if sync || self.pending >= CHECKPOINT_EVERY || self.checkpointed.elapsed() >= CHECKPOINT_INTERVAL {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> std::io::Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        write_checkpoint(&self.path, self.seq - 1, &self.hash)?;
+        self.pending = 0;
+        self.checkpointed = Instant::now();
+        Ok(())
+    }
+}
+
+/// SHA-256 over the record's JSON without its `hash` field.
+fn digest(record: &Value) -> String {
+    format!("{:x}", Sha256::digest(record.to_string().as_bytes()))
+}
+
+/// The last record's seq and hash, written next to the log so that cutting
+/// records off the end is detected.
+fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".head");
+    PathBuf::from(name)
+}
+
+fn write_checkpoint(path: &Path, seq: u64, hash: &str) -> std::io::Result<()> {
+    let head = checkpoint_path(path);
+    let tmp = head.with_extension("tmp");
+    std::fs::write(&tmp, format!("{}\n", json!({ "seq": seq, "hash": hash })))?;
+    std::fs::rename(tmp, head)
+}
+
+/// Where an existing log left off.
+struct Tail {
+    /// The seq and hash to continue from, those of the last record that parses
+    seq: u64,
+    hash: String,
+    /// Hash of record `at` (the checkpoint's), if the log still has it
+    at_hash: Option<String>,
+    /// Lines after that record that are not records, e.g. one cut off by a
+    /// power loss
+    damaged: u64,
+    /// The file does not end with a newline
+    unterminated: bool,
+}
+
+fn last_record(path: &Path, at: Option<u64>) -> Result<Tail> {
+    let mut tail = Tail { seq: 0, hash: GENESIS.to_string(), at_hash: None, damaged: 0, unterminated: false };
+    let io = |e: std::io::Error| DmsError::Audit(format!("{}: {}", path.display(), e));
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(tail),
+        Err(e) => return Err(io(e)),
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).map_err(io)? == 0 {
+            break;
+        }
+        tail.unterminated = line.last() != Some(&b'\n');
+        let record: Value = serde_json::from_slice(&line).unwrap_or_default();
+        match (record["seq"].as_u64(), record["hash"].as_str()) {
+            (Some(seq), Some(hash)) => {
+                if Some(seq) == at {
+                    tail.at_hash = Some(hash.to_string());
+                }
+                tail.seq = seq + 1;
+                tail.hash = hash.to_string();
+                tail.damaged = 0;
+            }
+            _ => tail.damaged += 1,
+        }
+    }
+    Ok(tail)
+}
+
+/// Result of a successful `verify`.
+#[derive(Debug)]
+pub struct Verified {
+    pub records: u64,
+    /// Hash of the last record; worth keeping off the machine
+    pub head: Option<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Checks the chain: every record's hash, its link to the previous one, the
+/// sequence numbers, and the checkpoint against the end of the file.
+pub fn verify(path: &Path) -> Result<Verified> {
+    let content = std::fs::read(path).map_err(|e| DmsError::Audit(e.to_string()))?;
+    let content = String::from_utf8(content)
+        .map_err(|_| DmsError::Audit("log is not valid UTF-8".into()))?;
+
+    let fail = |line: usize, msg: &str| DmsError::Audit(format!("line {}: {}", line, msg));
+    let mut hashes: Vec<String> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut prev = GENESIS.to_string();
+    // Lines that are not records, only allowed right before a `damaged_tail`
+    let mut damaged: Vec<(usize, String)> = Vec::new();
+
+    for (i, line) in content.split_inclusive('\n').enumerate() {
+        let line_no = i + 1;
+        let Some(line) = line.strip_suffix('\n') else {
+            // Only ever the last line: cut off by a crash or power loss
+            warnings.push(format!("line {}: incomplete last record (truncated write)", line_no));
+            break;
+        };
+
+        let parsed = serde_json::from_str::<Value>(line).map_err(|e| format!("not a JSON record: {}", e))
+            .and_then(|mut record| {
+                let hash = record.as_object_mut()
+                    .and_then(|r| r.remove("hash"))
+                    .and_then(|h| h.as_str().map(String::from))
+                    .ok_or("missing hash")?;
+                Ok((record, hash))
+            });
+        let (record, hash) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                damaged.push((line_no, e));
+                continue;
+            }
+        };
+        if let Some((first, e)) = damaged.first() {
+            if record["kind"] != "damaged_tail" || record["data"]["lines"].as_u64() != Some(damaged.len() as u64) {
+                return Err(fail(*first, e));
+            }
+            warnings.push(format!("lines {}-{}: damaged records, skipped on a restart", first, line_no - 1));
+            damaged.clear();
+        }
+
+        let seq = hashes.len() as u64;
+        if record["seq"].as_u64() != Some(seq) {
+            return Err(fail(line_no, &format!("expected seq {}, found {}", seq, record["seq"])));
+        }
+        if record["prev"].as_str() != Some(prev.as_str()) {
+            return Err(fail(line_no, "does not chain to the previous record"));
+        }
+        if digest(&record) != hash {
+            return Err(fail(line_no, "hash mismatch (record was modified)"));
+        }
+        // A restart found the end cut off; the chain itself continues
+        if record["kind"] == "checkpoint_mismatch" {
+            warnings.push(format!("line {}: records were cut off before a restart", line_no));
+        }
+        prev = hash.clone();
+        hashes.push(hash);
+    }
+    if let Some((first, e)) = damaged.first() {
+        return Err(fail(*first, e));
+    }
+
+    match std::fs::read_to_string(checkpoint_path(path)) {
+        Ok(head) => {
+            let head: Value = serde_json::from_str(&head)
+                .map_err(|e| DmsError::Audit(format!("checkpoint is damaged: {}", e)))?;
+            let seq = head["seq"].as_u64()
+                .ok_or_else(|| DmsError::Audit("checkpoint has no seq".into()))?;
+            match hashes.get(seq as usize) {
+                None => return Err(DmsError::Audit(format!(
+                    "log is truncated: checkpoint is at seq {} but the log has {} records", seq, hashes.len()))),
+                Some(hash) if head["hash"].as_str() != Some(hash.as_str()) => {
+                    return Err(DmsError::Audit(format!("checkpoint does not match record seq {}", seq)));
+                }
+                Some(_) if seq + 1 < hashes.len() as u64 => warnings.push(format!(
+                    "{} records after the checkpoint (seq {})", hashes.len() as u64 - seq - 1, seq)),
+                Some(_) => {}
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warnings.push("no checkpoint file; records cut off the end cannot be detected".into());
+        }
+        Err(e) => return Err(DmsError::Audit(format!("checkpoint: {}", e))),
+    }
+
+    Ok(Verified { records: hashes.len() as u64, head: hashes.pop(), warnings })
+}
+
+/// Runs `verify` for `audit verify` and prints the outcome. Returns whether
+/// the chain is intact.
+pub fn report(path: &Path) -> bool {
+    match verify(path) {
+        Ok(verified) => {
+            println!("ok: {} records in {}", verified.records, path.display());
+            if let Some(head) = verified.head {
+                println!("head: {}", head);
+            }
+            for warning in &verified.warnings {
+                println!("warning: {}", warning);
+            }
+            true
+        }
+        Err(e) => {
+            println!("FAILED: {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dms-audit-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("audit.jsonl")
+    }
+
+    /// A log of `n` records with its checkpoint written.
+    fn write_log(path: &Path, n: u64) {
+        let (mut log, _) = AuditLog::open(path).unwrap();
+        for i in 0..n {
+            log.append("test", json!({ "i": i }), false).unwrap();
+        }
+        log.checkpoint().unwrap();
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap().lines().map(String::from).collect()
+    }
+
+    fn write_lines(path: &Path, lines: &[String]) {
+        std::fs::write(path, lines.iter().map(|l| format!("{}\n", l)).collect::<String>()).unwrap();
+    }
+
+    fn error(path: &Path) -> String {
+        verify(path).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn verifies_intact_log() {
+        let path = scratch("intact");
+        write_log(&path, 5);
+        let verified = verify(&path).unwrap();
+        assert_eq!(verified.records, 5);
+        assert!(verified.warnings.is_empty(), "{:?}", verified.warnings);
+    }
+
+    #[test]
+    fn detects_edited_record() {
+        let path = scratch("edited");
+        write_log(&path, 5);
+        let mut records = lines(&path);
+        records[2] = records[2].replace(r#""i":2"#, r#""i":7"#);
+        write_lines(&path, &records);
+        assert!(error(&path).contains("line 3: hash mismatch"));
+    }
+
+    #[test]
+    fn detects_deleted_record() {
+        let path = scratch("deleted");
+        write_log(&path, 5);
+        let mut records = lines(&path);
+        records.remove(2);
+        write_lines(&path, &records);
+        assert!(error(&path).contains("line 3: expected seq 2"));
+    }
+
+    #[test]
+    fn detects_truncated_tail() {
+        let path = scratch("truncated");
+        write_log(&path, 5);
+        let mut records = lines(&path);
+        records.truncate(3);
+        write_lines(&path, &records);
+        assert!(error(&path).contains("log is truncated: checkpoint is at seq 4"));
+
+        // Restarting notices too, and says so in the log itself
+        let (_, mismatch) = AuditLog::open(&path).unwrap();
+        assert_eq!(mismatch.map(|(head, seq)| (head["seq"].as_u64(), seq)), Some((Some(4), 3)));
+    }
+
+    #[test]
+    fn recovers_from_damaged_tail() {
+        let path = scratch("damaged");
+        write_log(&path, 3);
+        // A record cut off by a power loss
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"seq":3,"ki"#).unwrap();
+        drop(file);
+        let verified = verify(&path).unwrap();
+        assert_eq!(verified.warnings, vec!["line 4: incomplete last record (truncated write)"]);
+
+        // Restarting chains on from the last good record
+        let (mut log, mismatch) = AuditLog::open(&path).unwrap();
+        assert!(mismatch.is_none());
+        assert_eq!(log.seq, 4);
+        log.append("test", json!({}), true).unwrap();
+        let records = lines(&path);
+        assert_eq!(records[3], r#"{"seq":3,"ki"#);
+        assert!(records[4].contains(r#""kind":"damaged_tail""#));
+        let verified = verify(&path).unwrap();
+        assert_eq!(verified.records, 5);
+        assert_eq!(verified.warnings, vec!["lines 4-4: damaged records, skipped on a restart"]);
+
+        // Garbage elsewhere is still an error
+        let mut records = lines(&path);
+        records.insert(1, "garbage".to_string());
+        write_lines(&path, &records);
+        assert!(error(&path).contains("line 2: not a JSON record"));
+    }
+
+    #[test]
+    fn detects_rechained_records() {
+        let path = scratch("rechained");
+        write_log(&path, 5);
+        let head = verify(&path).unwrap().head;
+
+        // Re-hashing the edited record alone breaks the link to the next one
+        let mut records = lines(&path);
+        let rehash = |line: &str, prev: Option<&str>| {
+            let mut record: Value = serde_json::from_str(line).unwrap();
+            record.as_object_mut().unwrap().remove("hash");
+            if let Some(prev) = prev {
+                record["prev"] = json!(prev);
+            }
+            let hash = digest(&record);
+            record["hash"] = json!(hash);
+            (record.to_string(), hash)
+        };
+        let (edited, mut prev) = rehash(&records[2].replace(r#""i":2"#, r#""i":7"#), None);
+        records[2] = edited;
+        write_lines(&path, &records);
+        assert!(error(&path).contains("line 4: does not chain"));
+
+        // Re-chaining the rest fails against the checkpoint...
+        for record in records.iter_mut().skip(3) {
+            let (line, hash) = rehash(record, Some(&prev));
+            *record = line;
+            prev = hash;
+        }
+        write_lines(&path, &records);
+        assert!(error(&path).contains("checkpoint does not match record seq 4"));
+
+        // ...and with the checkpoint rewritten as well, only a head kept
+        // elsewhere tells
+        write_checkpoint(&path, 4, &prev).unwrap();
+        assert_ne!(verify(&path).unwrap().head, head);
+    }
+
+    #[test]
+    fn batches_checkpoints() {
+        let path = scratch("batched");
+        let head = || std::fs::read_to_string(checkpoint_path(&path)).ok()
+            .map(|h| serde_json::from_str::<Value>(&h).unwrap()["seq"].as_u64().unwrap());
+        let (mut log, _) = AuditLog::open(&path).unwrap();
+
+        for _ in 0..3 {
+            log.append("packet", json!({}), false).unwrap();
+        }
+        assert_eq!(head(), None);
+        log.append("activation", json!({}), true).unwrap();
+        assert_eq!(head(), Some(3));
+        for _ in 0..CHECKPOINT_EVERY {
+            log.append("packet", json!({}), false).unwrap();
+        }
+        assert_eq!(head(), Some(CHECKPOINT_EVERY + 3));
+
+        // Records after the checkpoint (e.g. a crash before a flush) are not
+        // a truncation
+        log.append("packet", json!({}), false).unwrap();
+        drop(log);
+        let (log, mismatch) = AuditLog::open(&path).unwrap();
+        assert!(mismatch.is_none());
+        assert_eq!(log.seq, CHECKPOINT_EVERY + 5);
+        assert_eq!(verify(&path).unwrap().warnings, vec![format!("1 records after the checkpoint (seq {})", CHECKPOINT_EVERY + 3)]);
+    }
+}
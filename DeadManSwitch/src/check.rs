@@ -0,0 +1,196 @@
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use teloxide::prelude::*;
+use crate::actions::ActionExecutor;
+use crate::config::{ActionKind, Config};
+use crate::control::{self, Request, Response};
+use crate::policy;
+use crate::veracrypt;
+use crate::error::Result;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct CheckItem {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl CheckItem {
+    fn new(name: &'static str, result: std::result::Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Self { name, passed: true, detail },
+            Err(detail) => Self { name, passed: false, detail },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct CheckOptions {
+    pub probe_flic: bool,
+    pub probe_telegram: bool,
+}
+
+/// Validates the config and every action prerequisite without arming.
+/// Returns one item per check; nothing is triggered or executed.
+pub fn run(config: Result<Config>, opts: CheckOptions) -> Vec<CheckItem> {
+    let config = match config {
+        Ok(c) => c,
+        Err(e) => return vec![CheckItem::new("config", Err(e.to_string()))],
+    };
+
+    let mut items = vec![CheckItem::new("config", Ok("parsed".into()))];
+
+    // Only the tools some profile can run, once each
+    let mut checked = Vec::new();
+    for action in policy::actions_in_use(&config) {
+        let program = match action.kind {
+            ActionKind::Broadcast => continue,
+            ActionKind::LockScreen => ActionExecutor::lock_command().0,
+            ActionKind::DismountVeracrypt => veracrypt::program(&action),
+            ActionKind::Shutdown => ActionExecutor::shutdown_command().0,
+            ActionKind::Exec => action.program.as_deref().unwrap_or_default(),
+            ActionKind::CloseLuks => "cryptsetup",
+        };
+        if checked.contains(&(action.kind, program.to_string())) {
+            continue;
+        }
+        checked.push((action.kind, program.to_string()));
+        items.push(CheckItem::new(action.name(), check_executable(program)));
+    }
+
+    // Only what the enabled triggers need
+    if config.triggers.network.enabled {
+        items.push(CheckItem::new("udp port", check_udp_port(&config)));
+    }
+    if config.triggers.usb.enabled {
+        items.push(CheckItem::new("libusb", check_libusb()));
+    }
+
+    if opts.probe_flic {
+        items.push(CheckItem::new("flic", probe_flic(&config)));
+    }
+
+    if opts.probe_telegram {
+        items.push(CheckItem::new("telegram", probe_telegram(&config)));
+    }
+
+    items
+}
+
+pub fn print_table(items: &[CheckItem]) {
+    let width = items.iter().map(|i| i.name.len()).max().unwrap_or(0).max(5);
+
+    println!("{:<width$}  {:<6}  DETAIL", "CHECK", "STATUS", width = width);
+    for item in items {
+        let status = if item.passed { "PASS" } else { "FAIL" };
+        println!("{:<width$}  {:<6}  {}", item.name, status, item.detail, width = width);
+    }
+}
+
+fn check_executable(program: &str) -> std::result::Result<String, String> {
+    let path = find_executable(program)
+        .ok_or_else(|| format!("{} not found", program))?;
+
+    if is_executable(&path) {
+        Ok(path.display().to_string())
+    } else {
+        Err(format!("{} is not executable", path.display()))
+    }
+}
+
+/// Resolves `program` the way `Command::new` would: as-is when it contains a
+/// path separator, otherwise by searching `PATH`.
+pub(crate) fn find_executable(program: &str) -> Option<PathBuf> {
+    let candidate = Path::new(program);
+    if candidate.components().count() > 1 {
+        return candidate.is_file().then(|| candidate.to_path_buf());
+    }
+
+    let path_var = std::env::var_os("PATH")?;
+    std::env::split_paths(&path_var).find_map(|dir| {
+        let full = dir.join(program);
+        if full.is_file() {
+            return Some(full);
+        }
+        if cfg!(windows) {
+            let exe = dir.join(format!("{}.exe", program));
+            if exe.is_file() {
+                return Some(exe);
+            }
+        }
+        None
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path)
+        .map(|m| m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn check_udp_port(config: &Config) -> std::result::Result<String, String> {
+    let port = config.triggers.network.port;
+    match UdpSocket::bind(("0.0.0.0", port)) {
+        Ok(_) => Ok(format!("0.0.0.0:{} bindable", port)),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && network_up_in_running_instance(config) => {
+            Ok(format!("0.0.0.0:{} in use by the running instance", port))
+        }
+        Err(e) => Err(format!("cannot bind 0.0.0.0:{}: {}", port, e)),
+    }
+}
+
+/// Whether the instance behind the control socket has its network trigger
+/// up, i.e. holds the port itself.
+fn network_up_in_running_instance(config: &Config) -> bool {
+    if !config.control.enabled {
+        return false;
+    }
+    match control::request(&config.control.socket_path(), &Request::Status) {
+        Ok(Response { status: Some(status), .. }) => {
+            status.triggers.iter().any(|t| t.name == "network" && t.state == "up")
+        }
+        _ => false,
+    }
+}
+
+fn check_libusb() -> std::result::Result<String, String> {
+    use rusb::UsbContext;
+
+    let ctx = rusb::Context::new().map_err(|e| format!("libusb init failed: {}", e))?;
+    let devices = ctx.devices().map_err(|e| format!("enumeration failed: {}", e))?;
+    Ok(format!("{} devices enumerated", devices.len()))
+}
+
+fn probe_flic(config: &Config) -> std::result::Result<String, String> {
+    let addr: SocketAddr = format!("{}:{}", config.triggers.flic.ip, config.triggers.flic.port)
+        .parse()
+        .map_err(|e| format!("invalid address: {}", e))?;
+
+    TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)
+        .map(|_| format!("{} reachable", addr))
+        .map_err(|e| format!("{}: {}", addr, e))
+}
+
+fn probe_telegram(config: &Config) -> std::result::Result<String, String> {
+    let token = config.telegram_token().map_err(|e| e.to_string())?;
+
+    let rt = tokio::runtime::Runtime::new().map_err(|e| e.to_string())?;
+    let bot = Bot::new(token);
+
+    rt.block_on(async {
+        match tokio::time::timeout(PROBE_TIMEOUT, bot.get_me()).await {
+            Ok(Ok(me)) => Ok(format!("bot @{}", me.username())),
+            Ok(Err(e)) => Err(format!("get_me failed: {}", e)),
+            Err(_) => Err("get_me timed out".into()),
+        }
+    })
+}
@@ -1,60 +1,868 @@
 use local_ip_address::local_ip;
 use regex::Regex;
+use clap::builder::BoolishValueParser;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
 use crate::error::{DmsError, Result};
+use crate::secret::{Secret, SecretRef};
 
 lazy_static::lazy_static! {
-    static ref IPV4_REGEX: Regex = 
+    static ref IPV4_REGEX: Regex =
         Regex::new(r"^(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9][0-9]?)$").unwrap();
+    static ref TELEGRAM_TOKEN_REGEX: Regex =
+        Regex::new(r"^[0-9]{5,}:[A-Za-z0-9_-]{30,}$").unwrap();
 }
 
-#[derive(Clone, Debug)]
+const SYSTEM_CONFIG_PATH: &str = "/etc/dms/config.toml";
+
+/// Trigger names, in start order.
+pub const TRIGGER_NAMES: [&str; 5] = ["timer", "telegram", "network", "usb", "flic"];
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
-    pub telegram_bot_token: String,
-    pub telegram_heartbeat_timeout: u64,  // ← NEW: seconds without heartbeat before trigger
-    pub broadcast_port: u16,
-    pub broadcast_message: String,
-    pub telegram_command: String,
-    pub usb_vendor_id: u16,
-    pub usb_product_id: u16,
-    pub flic_ip: String,
-    pub flic_port: u16,
+    /// Literal token or a `file:`, `env:` or `keyring:` reference
+    pub telegram_bot_token: Option<Secret>,
+    /// Chat that receives status announcements, e.g. schedule changes
+    pub telegram_chat_id: Option<i64>,
+    pub triggers: TriggersConfig,
+    pub supervisor: SupervisorConfig,
+    pub control: ControlConfig,
+    pub arming: ArmingConfig,
+    pub audit: AuditConfig,
+    pub logging: LoggingConfig,
+    /// Steps of the built-in `full` profile, see `policy`
+    pub actions: Vec<ActionConfig>,
+    /// Named action profiles, see `policy`
+    pub profiles: BTreeMap<String, ProfileConfig>,
+    pub policy: PolicyConfig,
+    /// Compound trigger rules, see `rules`
+    pub rules: Vec<RuleConfig>,
+    /// When each trigger is armed, see `schedule`; absent means always
+    pub schedules: BTreeMap<String, Vec<String>>,
+    /// `--dry-run`: actions are only logged. Never read from the file.
+    #[serde(skip)]
+    pub dry_run: bool,
+}
+
+/// Restart policy for trigger monitors that exit or fail.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisorConfig {
+    pub max_restarts: u32,
+    pub backoff: u64,      // seconds before the first restart, doubled each time
+    pub max_backoff: u64,  // seconds
+    pub startup_timeout: u64,  // seconds to wait for triggers to report ready
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self { max_restarts: 10, backoff: 1, max_backoff: 300, startup_timeout: 15 }
+    }
+}
+
+/// Local control socket used by `DeadManSwitch ctl` (Unix only).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    pub socket: Option<PathBuf>,  // default: see `control::default_socket_path`
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self { enabled: true, socket: None }
+    }
+}
+
+impl ControlConfig {
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket.clone().unwrap_or_else(crate::control::default_socket_path)
+    }
+}
+
+/// Disarming or pausing the switch requires the PIN hashed in `pin_hash`
+/// (Argon2 PHC string, see `DeadManSwitch hash-pin`). Without it both are
+/// refused.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArmingConfig {
+    pub pin_hash: Option<String>,
+    pub grace_period: u64,  // seconds after startup or a re-arm before triggers fire
+}
+
+impl Default for ArmingConfig {
+    fn default() -> Self {
+        Self { pin_hash: None, grace_period: 30 }
+    }
+}
+
+/// Hash-chained JSON-lines record of everything the switch sees and does.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    pub path: Option<PathBuf>,  // default: see `audit::default_path`
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self { enabled: true, path: None }
+    }
+}
+
+impl AuditConfig {
+    pub fn path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(crate::audit::default_path)
+    }
+}
+
+/// Log sinks, each with its own level. `level` is the terminal's.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    pub file: FileLogConfig,
+    pub syslog: SyslogConfig,
+    pub journald: JournaldConfig,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+            file: FileLogConfig::default(),
+            syslog: SyslogConfig::default(),
+            journald: JournaldConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+/// Log file, rotated to `<path>.1` .. `<path>.<keep>` when it grows past
+/// `max_size`, unless that is 0.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileLogConfig {
+    pub enabled: bool,
+    pub path: Option<PathBuf>,  // default: see `logging::default_path`
+    pub level: LogLevel,
+    pub max_size: u64,  // bytes, 0: never rotate
+    pub keep: u32,      // rotated files
+}
+
+impl Default for FileLogConfig {
+    fn default() -> Self {
+        Self { enabled: false, path: None, level: LogLevel::Info, max_size: 10 * 1024 * 1024, keep: 5 }
+    }
+}
+
+impl FileLogConfig {
+    pub fn path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(crate::logging::default_path)
+    }
+}
+
+/// RFC 5424 syslog. `target` is `unix:<socket>` or `udp:<host>[:<port>]`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    pub enabled: bool,
+    pub target: String,
+    pub facility: String,
+    pub app_name: String,
+    pub level: LogLevel,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            target: "unix:/dev/log".to_string(),
+            facility: "daemon".to_string(),
+            app_name: "dms".to_string(),
+            level: LogLevel::Info,
+        }
+    }
+}
+
+/// Native systemd journal (Linux only), with `DMS_*` fields.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournaldConfig {
+    pub enabled: bool,
+    pub level: LogLevel,
+}
+
+impl Default for JournaldConfig {
+    fn default() -> Self {
+        Self { enabled: false, level: LogLevel::Info }
+    }
+}
+
+/// The actions a profile runs, in order, and what the switch does after.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub actions: Vec<ActionConfig>,
+    /// default: `exit` if the profile shuts down, else `arm`
+    pub after: Option<AfterProfile>,
+}
+
+/// What the switch does once a profile has run.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AfterProfile {
+    /// Stop the switch, e.g. after a wipe
+    Exit,
+    /// Re-arm after the grace period
+    Arm,
+    Disarm,
+}
+
+/// One step of a pipeline. A step whose command outlives `timeout` is
+/// killed; a failed step stops the pipeline unless `continue_on_error`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ActionConfig {
+    #[serde(rename = "type")]
+    pub kind: ActionKind,
+    #[serde(default)]
+    pub timeout: Option<u64>,  // seconds, default: see `ActionKind::default_timeout`
+    #[serde(default = "default_true")]
+    pub continue_on_error: bool,
+    #[serde(default)]
+    pub delay: u64,  // seconds to wait before the step
+    /// `exec`: the program to run, its arguments and working directory.
+    /// `dismount_veracrypt`: the VeraCrypt binary
+    #[serde(default)]
+    pub program: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    /// `close_luks`: mapping names; empty closes every dm-crypt mapping
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mappings: Vec<String>,
+    /// `dismount_veracrypt`: volume paths or mount points; empty dismounts all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+    /// `dismount_veracrypt`: attempts after a failed first one
+    #[serde(default)]
+    pub retries: Option<u32>,
+}
+
+impl ActionConfig {
+    pub fn new(kind: ActionKind) -> Self {
+        Self {
+            kind,
+            timeout: None,
+            continue_on_error: true,
+            delay: 0,
+            program: None,
+            args: vec![],
+            working_dir: None,
+            mappings: vec![],
+            volumes: vec![],
+            retries: None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.kind.name()
+    }
+
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout.unwrap_or_else(|| self.kind.default_timeout()))
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    /// Send the trigger broadcast to the LAN
+    Broadcast,
+    LockScreen,
+    #[serde(alias = "veracrypt_dismount")]
+    DismountVeracrypt,
+    Shutdown,
+    /// Run `program` with the trigger event in `DMS_*` variables
+    Exec,
+    /// Unmount and close dm-crypt (LUKS) mappings (Linux only)
+    #[serde(alias = "luks_close")]
+    CloseLuks,
+}
+
+impl ActionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ActionKind::Broadcast => "broadcast",
+            ActionKind::LockScreen => "lock_screen",
+            ActionKind::DismountVeracrypt => "dismount_veracrypt",
+            ActionKind::Shutdown => "shutdown",
+            ActionKind::Exec => "exec",
+            ActionKind::CloseLuks => "close_luks",
+        }
+    }
+
+    /// Seconds; the broadcast does not block and ignores it.
+    pub fn default_timeout(&self) -> u64 {
+        match self {
+            ActionKind::Broadcast => 5,
+            ActionKind::LockScreen => 10,
+            ActionKind::DismountVeracrypt => 60,
+            ActionKind::Shutdown => 30,
+            ActionKind::Exec => 60,
+            ActionKind::CloseLuks => 60,
+        }
+    }
+}
+
+/// Which profile each trigger source runs. Sources without an entry use
+/// `default`, or the built-in `full` profile when that is unset.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub default: Option<String>,
+    pub sources: BTreeMap<String, String>,
+}
+
+/// Fires when its sources' events combine as `kind` requires within
+/// `window`. Sources named in any rule only fire through rules.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    pub kind: RuleKind,
+    pub sources: Vec<String>,
+    pub count: Option<usize>,  // for `at_least`
+    pub window: u64,           // seconds
+}
+
+impl Default for RuleConfig {
+    fn default() -> Self {
+        Self { name: String::new(), kind: RuleKind::All, sources: vec![], count: None, window: 600 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    /// Any one of the sources
+    Any,
+    /// Every source
+    All,
+    /// `count` distinct sources
+    AtLeast,
+    /// Every source, in the listed order
+    Sequence,
+}
+
+/// One section per trigger. Sections of triggers that are not registered
+/// are rejected by `TriggerRegistry::validate`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TriggersConfig {
+    pub timer: TimerConfig,
+    pub telegram: TelegramConfig,
+    pub network: NetworkConfig,
+    pub usb: UsbConfig,
+    pub flic: FlicConfig,
+    /// Sections of triggers registered out of tree, read with `section`
+    #[serde(flatten)]
+    pub other: BTreeMap<String, toml::Table>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimerConfig {
+    pub enabled: bool,
+    pub timeout: u64,  // seconds without heartbeat before trigger
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+    pub enabled: bool,
+    pub command: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub enabled: bool,
+    pub port: u16,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsbConfig {
+    pub enabled: bool,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlicConfig {
+    pub enabled: bool,
+    pub ip: String,
+    pub port: u16,
+}
+
+impl Default for TimerConfig {
+    fn default() -> Self {
+        Self { enabled: true, timeout: 3600 }
+    }
+}
+
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        Self { enabled: true, command: "execute".to_string() }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self { enabled: true, port: 45370, message: "trigger_dms".to_string() }
+    }
+}
+
+impl Default for UsbConfig {
+    fn default() -> Self {
+        Self { enabled: true, vendor_id: 0x090c, product_id: 0x1000 }
+    }
+}
+
+impl Default for FlicConfig {
+    fn default() -> Self {
+        Self { enabled: true, ip: "auto".to_string(), port: 5551 }
+    }
+}
+
+impl TriggersConfig {
+    /// The `[triggers.<name>]` section of a trigger registered out of tree,
+    /// as its own type; the type's defaults if the section is missing.
+    pub fn section<T: DeserializeOwned + Default>(&self, name: &str) -> std::result::Result<T, String> {
+        match self.other.get(name) {
+            Some(table) => T::deserialize(toml::Value::Table(table.clone()))
+                .map_err(|e| format!("triggers.{}: {}", name, e)),
+            None => Ok(T::default()),
+        }
+    }
+
+    fn set_enabled(&mut self, name: &str, enabled: bool) {
+        match name {
+            "timer" => self.timer.enabled = enabled,
+            "telegram" => self.telegram.enabled = enabled,
+            "network" => self.network.enabled = enabled,
+            "usb" => self.usb.enabled = enabled,
+            "flic" => self.flic.enabled = enabled,
+            other => {
+                self.other.entry(other.to_string()).or_default().insert("enabled".into(), enabled.into());
+            }
+        }
+    }
+}
+
+// Per-field overrides taken from `DMS_*` environment variables and CLI flags.
+// Clap resolves CLI over env; both win over the config file. (Plain comment on
+// purpose: a doc comment would become the `--help` description.)
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Overrides {
+    /// Comma-separated triggers to run (overrides the `enabled` flags):
+    /// all, timer, telegram (bot), network (net), usb, flic
+    #[clap(short, long, value_delimiter = ',', value_parser = parse_trigger_name)]
+    pub mode: Option<Vec<String>>,
+
+    #[clap(long, env = "DMS_TELEGRAM_BOT_TOKEN", hide_env_values = true, value_parser = SecretRef::parse)]
+    pub telegram_bot_token: Option<SecretRef>,
+
+    #[clap(long, env = "DMS_TELEGRAM_CHAT_ID")]
+    pub telegram_chat_id: Option<i64>,
+
+    #[clap(long, env = "DMS_TIMER_TIMEOUT")]
+    pub timer_timeout: Option<u64>,
+
+    #[clap(long, env = "DMS_TELEGRAM_COMMAND")]
+    pub telegram_command: Option<String>,
+
+    #[clap(long, env = "DMS_NETWORK_PORT")]
+    pub network_port: Option<u16>,
+
+    #[clap(long, env = "DMS_NETWORK_MESSAGE")]
+    pub network_message: Option<String>,
+
+    #[clap(long, env = "DMS_USB_VENDOR_ID", value_parser = parse_u16)]
+    pub usb_vendor_id: Option<u16>,
+
+    #[clap(long, env = "DMS_USB_PRODUCT_ID", value_parser = parse_u16)]
+    pub usb_product_id: Option<u16>,
+
+    #[clap(long, env = "DMS_FLIC_IP")]
+    pub flic_ip: Option<String>,
+
+    #[clap(long, env = "DMS_FLIC_PORT")]
+    pub flic_port: Option<u16>,
+
+    #[clap(long, env = "DMS_CONTROL_SOCKET")]
+    pub control_socket: Option<PathBuf>,
+
+    #[clap(long, env = "DMS_GRACE_PERIOD")]
+    pub grace_period: Option<u64>,
+
+    #[clap(long, env = "DMS_AUDIT_LOG")]
+    pub audit_log: Option<PathBuf>,
+
+    #[clap(long, env = "DMS_LOG_LEVEL", value_parser = parse_log_level)]
+    pub log_level: Option<LogLevel>,
+
+    #[clap(long, env = "DMS_TIMER_ENABLED", value_parser = BoolishValueParser::new())]
+    pub timer_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_TELEGRAM_ENABLED", value_parser = BoolishValueParser::new())]
+    pub telegram_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_NETWORK_ENABLED", value_parser = BoolishValueParser::new())]
+    pub network_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_USB_ENABLED", value_parser = BoolishValueParser::new())]
+    pub usb_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_FLIC_ENABLED", value_parser = BoolishValueParser::new())]
+    pub flic_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_MAX_RESTARTS")]
+    pub max_restarts: Option<u32>,
+
+    #[clap(long, env = "DMS_BACKOFF")]
+    pub backoff: Option<u64>,
+
+    #[clap(long, env = "DMS_MAX_BACKOFF")]
+    pub max_backoff: Option<u64>,
+
+    #[clap(long, env = "DMS_STARTUP_TIMEOUT")]
+    pub startup_timeout: Option<u64>,
+
+    #[clap(long, env = "DMS_CONTROL_ENABLED", value_parser = BoolishValueParser::new())]
+    pub control_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_AUDIT_ENABLED", value_parser = BoolishValueParser::new())]
+    pub audit_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_PIN_HASH")]
+    pub pin_hash: Option<String>,
+
+    #[clap(long, env = "DMS_LOG_FILE_ENABLED", value_parser = BoolishValueParser::new())]
+    pub log_file_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    #[clap(long, env = "DMS_LOG_FILE_LEVEL", value_parser = parse_log_level)]
+    pub log_file_level: Option<LogLevel>,
+
+    #[clap(long, env = "DMS_LOG_FILE_MAX_SIZE")]
+    pub log_file_max_size: Option<u64>,
+
+    #[clap(long, env = "DMS_LOG_FILE_KEEP")]
+    pub log_file_keep: Option<u32>,
+
+    #[clap(long, env = "DMS_SYSLOG_ENABLED", value_parser = BoolishValueParser::new())]
+    pub syslog_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_SYSLOG_TARGET")]
+    pub syslog_target: Option<String>,
+
+    #[clap(long, env = "DMS_SYSLOG_FACILITY")]
+    pub syslog_facility: Option<String>,
+
+    #[clap(long, env = "DMS_SYSLOG_APP_NAME")]
+    pub syslog_app_name: Option<String>,
+
+    #[clap(long, env = "DMS_SYSLOG_LEVEL", value_parser = parse_log_level)]
+    pub syslog_level: Option<LogLevel>,
+
+    #[clap(long, env = "DMS_JOURNALD_ENABLED", value_parser = BoolishValueParser::new())]
+    pub journald_enabled: Option<bool>,
+
+    #[clap(long, env = "DMS_JOURNALD_LEVEL", value_parser = parse_log_level)]
+    pub journald_level: Option<LogLevel>,
+
+    /// Run triggers as usual, but only log the commands the actions would run
+    #[clap(long, env = "DMS_DRY_RUN")]
+    pub dry_run: bool,
+}
+
+impl Overrides {
+    /// Fails if a secret reference cannot be resolved.
+    fn apply(&self, config: &mut Config) -> Result<()> {
+        macro_rules! apply {
+            ($($field:ident => $($path:ident).+),* $(,)?) => {
+                $(if let Some(v) = &self.$field {
+                    log::info!("[+] Config override: {}", stringify!($field));
+                    config.$($path).+ = v.clone();
+                })*
+            };
+        }
+
+        if let Some(token) = &self.telegram_bot_token {
+            log::info!("[+] Config override: telegram_bot_token");
+            let token = token.resolve().map_err(|e| match e {
+                DmsError::Config(msg) => DmsError::Config(format!("telegram_bot_token override: {}", msg)),
+                other => other,
+            })?;
+            config.telegram_bot_token = Some(token);
+        }
+
+        if let Some(chat_id) = self.telegram_chat_id {
+            log::info!("[+] Config override: telegram_chat_id");
+            config.telegram_chat_id = Some(chat_id);
+        }
+
+        apply!(
+            timer_timeout => triggers.timer.timeout,
+            telegram_command => triggers.telegram.command,
+            network_port => triggers.network.port,
+            network_message => triggers.network.message,
+            usb_vendor_id => triggers.usb.vendor_id,
+            usb_product_id => triggers.usb.product_id,
+            flic_ip => triggers.flic.ip,
+            flic_port => triggers.flic.port,
+            grace_period => arming.grace_period,
+            log_level => logging.level,
+            timer_enabled => triggers.timer.enabled,
+            telegram_enabled => triggers.telegram.enabled,
+            network_enabled => triggers.network.enabled,
+            usb_enabled => triggers.usb.enabled,
+            flic_enabled => triggers.flic.enabled,
+            max_restarts => supervisor.max_restarts,
+            backoff => supervisor.backoff,
+            max_backoff => supervisor.max_backoff,
+            startup_timeout => supervisor.startup_timeout,
+            control_enabled => control.enabled,
+            audit_enabled => audit.enabled,
+            log_file_enabled => logging.file.enabled,
+            log_file_level => logging.file.level,
+            log_file_max_size => logging.file.max_size,
+            log_file_keep => logging.file.keep,
+            syslog_enabled => logging.syslog.enabled,
+            syslog_target => logging.syslog.target,
+            syslog_facility => logging.syslog.facility,
+            syslog_app_name => logging.syslog.app_name,
+            syslog_level => logging.syslog.level,
+            journald_enabled => logging.journald.enabled,
+            journald_level => logging.journald.level,
+        );
+
+        if let Some(hash) = &self.pin_hash {
+            log::info!("[+] Config override: pin_hash");
+            config.arming.pin_hash = Some(hash.clone());
+        }
+
+        if let Some(path) = &self.log_file {
+            log::info!("[+] Config override: log_file");
+            config.logging.file.path = Some(path.clone());
+        }
+
+        if let Some(socket) = &self.control_socket {
+            log::info!("[+] Config override: control_socket");
+            config.control.socket = Some(socket.clone());
+        }
+
+        if let Some(path) = &self.audit_log {
+            log::info!("[+] Config override: audit_log");
+            config.audit.path = Some(path.clone());
+        }
+
+        if self.dry_run {
+            log::warn!("[!] Dry run: actions will only be logged");
+            config.dry_run = true;
+        }
+
+        if let Some(mode) = &self.mode {
+            log::info!("[+] Config override: mode {}", mode.join(","));
+            let all = mode.iter().any(|m| m == "all");
+            let others: Vec<String> = config.triggers.other.keys().cloned().collect();
+            let names = TRIGGER_NAMES.iter().map(|n| n.to_string())
+                .chain(others)
+                .chain(mode.iter().filter(|m| *m != "all").cloned())
+                .collect::<std::collections::BTreeSet<_>>();
+            for name in names {
+                let enabled = all || mode.contains(&name);
+                config.triggers.set_enabled(&name, enabled);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Maps the legacy short names of a `--mode` or `ctl` trigger. The name
+/// itself is checked against the trigger registry, which also knows the
+/// triggers registered out of tree.
+pub fn parse_trigger_name(value: &str) -> std::result::Result<String, String> {
+    match value.trim() {
+        "" => Err("empty trigger name".into()),
+        "bot" => Ok("telegram".into()),
+        "net" => Ok("network".into()),
+        name => Ok(name.to_string()),
+    }
+}
+
+/// The level names the config file takes, e.g. `debug`.
+fn parse_log_level(value: &str) -> std::result::Result<LogLevel, String> {
+    LogLevel::deserialize(toml::Value::String(value.trim().to_ascii_lowercase()))
+        .map_err(|_| format!("invalid level '{}' (expected off, error, warn, info, debug or trace)", value))
+}
+
+/// Accepts decimal or `0x`-prefixed hex, as USB IDs are usually written in hex.
+fn parse_u16(value: &str) -> std::result::Result<u16, String> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|e| format!("invalid value '{}': {}", value, e))
+}
+
+/// Where the running config came from, so it can be re-read on reload.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigSource {
+    pub path: Option<PathBuf>,
+    pub overrides: Overrides,
+}
+
+impl ConfigSource {
+    pub fn load(&self) -> Result<Config> {
+        Config::load(self.path.as_deref(), &self.overrides)
+    }
 }
 
 impl Config {
-    pub fn new(
-        telegram_bot_token: String,
-        telegram_heartbeat_timeout: u64,
-        broadcast_port: u16,
-        broadcast_message: String,
-        telegram_command: String,
-        usb_vendor_id: u16,
-        usb_product_id: u16,
-        mut flic_ip: String,
-        flic_port: u16,
-    ) -> Result<Self> {
-        if !IPV4_REGEX.is_match(&flic_ip) {
-            flic_ip = Self::auto_detect_flic_ip()?;
-            log::warn!("[!] Flic IP auto-detected: {}", flic_ip);
-        }
-        
-        Ok(Self {
-            telegram_bot_token,
-            telegram_heartbeat_timeout,
-            broadcast_port,
-            broadcast_message,
-            telegram_command,
-            usb_vendor_id,
-            usb_product_id,
-            flic_ip,
-            flic_port,
-        })
+    /// Loads the config from `path`, or from the first default location that
+    /// exists, then applies `overrides`. Falls back to the built-in defaults
+    /// when no file is found.
+    pub fn load(path: Option<&Path>, overrides: &Overrides) -> Result<Self> {
+        let path = match path {
+            Some(p) => Some(p.to_path_buf()),
+            None => Self::search_paths().into_iter().find(|p| p.is_file()),
+        };
+
+        let mut config = match path {
+            Some(p) => {
+                log::info!("[+] Loading config: {}", p.display());
+                Self::from_file(&p)?
+            }
+            None => {
+                log::warn!("[!] No config file found, using built-in defaults");
+                Self::default()
+            }
+        };
+
+        overrides.apply(&mut config)?;
+        config.resolve()
+    }
+
+    /// Renders the effective config as TOML. Secrets serialize redacted.
+    pub fn to_redacted_toml(&self) -> Result<String> {
+        toml::to_string(self)
+            .map_err(|e| DmsError::Config(format!("Failed to render config: {}", e)))
+    }
+
+    /// The Telegram bot token, if it is set and well-formed.
+    pub fn telegram_token(&self) -> Result<&str> {
+        let token = self.telegram_bot_token.as_ref()
+            .ok_or_else(|| DmsError::Config("telegram_bot_token is not set".into()))?;
+
+        if !TELEGRAM_TOKEN_REGEX.is_match(token.expose()) {
+            return Err(DmsError::Config(
+                "telegram_bot_token is malformed (expected <bot id>:<secret>)".into()
+            ));
+        }
+        Ok(token.expose())
+    }
+
+    /// Default config locations, in lookup order.
+    pub fn search_paths() -> Vec<PathBuf> {
+        let mut paths = vec![];
+
+        let xdg = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+        if let Some(dir) = xdg {
+            paths.push(dir.join("dms").join("config.toml"));
+        }
+
+        paths.push(PathBuf::from(SYSTEM_CONFIG_PATH));
+        paths
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| DmsError::Config(format!("{}: {}", path.display(), e)))?;
+        Self::from_toml(&content)
+            .map_err(|e| DmsError::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses a TOML document. Errors name the offending key and line.
+    pub fn from_toml(content: &str) -> std::result::Result<Self, String> {
+        toml::from_str(content).map_err(|e| describe_toml_error(content, &e))
+    }
+
+    /// Fills in values that are computed at runtime (e.g. `ip = "auto"`).
+    fn resolve(mut self) -> Result<Self> {
+        if let Some(hash) = &self.arming.pin_hash {
+            crate::pin::validate(hash)?;
+        }
+        crate::policy::validate(&self)?;
+        crate::rules::validate(&self.rules)?;
+        crate::schedule::parse_all(&self.schedules)?;
+        crate::logging::validate(&self.logging)?;
+
+        let flic = &mut self.triggers.flic;
+        if flic.enabled && !IPV4_REGEX.is_match(&flic.ip) {
+            flic.ip = Self::auto_detect_flic_ip()?;
+            log::warn!("[!] Flic IP auto-detected: {}", flic.ip);
+        }
+        Ok(self)
     }
 
+
     fn auto_detect_flic_ip() -> Result<String> {
         let local_ip = local_ip()
             .map_err(|e| DmsError::Config(format!("Failed to get local IP: {}", e)))?;
-        
+
         match local_ip {
             std::net::IpAddr::V4(ipv4) => {
                 let mut octets = ipv4.octets();
@@ -66,18 +874,147 @@ impl Config {
             }
         }
     }
+}
+
+fn describe_toml_error(content: &str, err: &toml::de::Error) -> String {
+    let message = err.message().trim();
+    let Some(span) = err.span() else {
+        return message.to_string();
+    };
+
+    let line_no = content[..span.start].matches('\n').count() + 1;
+    let line = content.lines().nth(line_no - 1).unwrap_or("");
+
+    match line.split_once('=') {
+        Some((key, _)) if !line.trim_start().starts_with('[') => {
+            // Qualify the key with its enclosing `[table]`, if any
+            let table = content.lines()
+                .take(line_no - 1)
+                .filter_map(|l| l.trim().strip_prefix('['))
+                .last()
+                .map(|h| h.trim_matches(|c| c == '[' || c == ']').trim().to_string());
+            let key = match table {
+                Some(table) => format!("{}.{}", table, key.trim()),
+                None => key.trim().to_string(),
+            };
+            format!("line {}, key `{}`: {}", line_no, key, message)
+        }
+        _ => format!("line {}: {}", line_no, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dms-config-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        overrides: Overrides,
+    }
+
+    fn resolved(toml: &str) -> Result<Config> {
+        Config::from_toml(&format!("[triggers.flic]\nenabled = false\n{}", toml))
+            .map_err(DmsError::Config)?
+            .resolve()
+    }
+
+    #[test]
+    fn toml_errors_name_key_and_line() {
+        let err = Config::from_toml("[triggers.network]\nport = 1\nprot = 2\n").unwrap_err();
+        assert!(err.starts_with("line 3, key `triggers.network.prot`: unknown field `prot`"), "{}", err);
+        let err = Config::from_toml("telegram_chat_id = 1\n\n[arming]\ngrace_period = \"soon\"\n").unwrap_err();
+        assert!(err.starts_with("line 4, key `arming.grace_period`: invalid type"), "{}", err);
+        let err = Config::from_toml("[triggers.usb\nenabled = true\n").unwrap_err();
+        assert!(err.starts_with("line 1: "), "{}", err);
+    }
+
+    #[test]
+    fn resolve_validates() {
+        assert!(resolved("").is_ok());
+        let err = |toml: &str| resolved(toml).unwrap_err().to_string();
+        assert!(err("[policy]\ndefault = \"nope\"\n").contains("policy.default: unknown profile 'nope'"));
+        assert!(err("[profiles.wipe]\nactions = [{ type = \"exec\" }]\n")
+            .contains("profiles.wipe.actions[0]: exec needs a program"));
+        assert!(err("[profiles.empty]\n").contains("profiles.empty has no actions"));
+        assert!(resolved("[arming]\npin_hash = \"not a hash\"\n").is_err());
+        assert!(resolved("[schedules]\nusb = [\"someday\"]\n").is_err());
+    }
+
+    // Environment variables are covered by tests/cli.rs, which runs the
+    // binary: setting them here would leak into tests running in parallel
+    #[test]
+    fn cli_over_file() {
+        let dir = scratch("precedence");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "[triggers.network]\nport = 1000\nmessage = \"from file\"\n\
+                               [triggers.flic]\nenabled = false\n[arming]\ngrace_period = 5\n").unwrap();
+        let load = |args: &[&str]| {
+            let cli = <Cli as clap::Parser>::try_parse_from(std::iter::once("dms").chain(args.iter().copied())).unwrap();
+            Config::load(Some(&path), &cli.overrides).unwrap()
+        };
+
+        let config = load(&[]);
+        assert_eq!((config.triggers.network.port, config.arming.grace_period), (1000, 5));
+        assert_eq!(config.logging.level, LogLevel::Info);
+
+        let config = load(&["--network-port", "3000", "--grace-period", "0", "--usb-vendor-id", "0x090c",
+                            "--audit-log", "/tmp/audit.jsonl", "--max-restarts", "2", "--log-level", "debug"]);
+        assert_eq!((config.triggers.network.port, config.arming.grace_period), (3000, 0));
+        assert_eq!(config.triggers.network.message, "from file");
+        assert_eq!(config.logging.level, LogLevel::Debug);
+        assert_eq!(config.triggers.usb.vendor_id, 0x090c);
+        assert_eq!(config.audit.path, Some(PathBuf::from("/tmp/audit.jsonl")));
+        assert_eq!(config.supervisor.max_restarts, 2);
+
+        let config = load(&["--network-enabled", "false", "--usb-enabled", "yes", "--control-enabled", "0",
+                            "--audit-enabled", "off", "--backoff", "2", "--max-backoff", "60", "--startup-timeout", "5",
+                            "--log-file-enabled", "true", "--log-file", "/tmp/dms.log", "--log-file-max-size", "8192",
+                            "--syslog-enabled", "1", "--syslog-facility", "local0", "--journald-level", "warn"]);
+        assert!(!config.triggers.network.enabled && config.triggers.usb.enabled);
+        assert!(!config.control.enabled && !config.audit.enabled);
+        assert_eq!((config.supervisor.backoff, config.supervisor.max_backoff, config.supervisor.startup_timeout), (2, 60, 5));
+        assert!(config.logging.file.enabled);
+        assert_eq!(config.logging.file.path, Some(PathBuf::from("/tmp/dms.log")));
+        assert_eq!(config.logging.file.max_size, 8192);
+        assert!(config.logging.syslog.enabled);
+        assert_eq!(config.logging.syslog.facility, "local0");
+        assert_eq!(config.logging.journald.level, LogLevel::Warn);
+
+        // Overrides are validated like the file
+        let cli = <Cli as clap::Parser>::try_parse_from(["dms", "--pin-hash", "not a hash"]).unwrap();
+        assert!(Config::load(Some(&path), &cli.overrides).is_err());
+        assert!(<Cli as clap::Parser>::try_parse_from(["dms", "--log-level", "loud"]).is_err());
+        assert!(<Cli as clap::Parser>::try_parse_from(["dms", "--usb-enabled", "maybe"]).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reload_resolves_token_reference_again() {
+        let dir = scratch("token");
+        let (path, token) = (dir.join("config.toml"), dir.join("token"));
+        std::fs::write(&path, "[triggers.flic]\nenabled = false\n").unwrap();
+        std::fs::write(&token, "12345:first\n").unwrap();
+        let reference = SecretRef::parse(&format!("file:{}", token.display())).unwrap();
+        let source = ConfigSource {
+            path: Some(path),
+            overrides: Overrides { telegram_bot_token: Some(reference), ..Default::default() },
+        };
+        let loaded = |source: &ConfigSource| source.load().unwrap().telegram_bot_token.unwrap();
 
-    pub fn default() -> Result<Self> {
-        Self::new(
-            "TELEGRAM_BOT_TOKEN".to_string(),
-            30,  // ← 1 hour timeout by default
-            45370,
-            "trigger_dms".to_string(),
-            "execute".to_string(),
-            0x090c,
-            0x1000,
-            "auto".to_string(),
-            5551,
-        )
+        assert_eq!(loaded(&source).expose(), "12345:first");
+        std::fs::write(&token, "12345:second\n").unwrap();
+        assert_eq!(loaded(&source).expose(), "12345:second");
+        std::fs::remove_file(&token).unwrap();
+        assert!(source.load().is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
-}
\ No newline at end of file
+}
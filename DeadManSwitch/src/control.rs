@@ -0,0 +1,585 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::sync::{mpsc, oneshot};
+use crate::config::{self, ControlConfig};
+use crate::error::{DmsError, Result};
+use crate::pin::{self, Pin};
+
+/// One JSON object per line, sent by `DeadManSwitch ctl`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub enum Request {
+    Status,
+    /// Re-arm the switch, or start a single disarmed trigger
+    Arm {
+        #[serde(default)]
+        trigger: Option<String>,
+    },
+    /// Disarm the switch, or stop a single trigger. Requires the PIN.
+    Disarm {
+        #[serde(default)]
+        trigger: Option<String>,
+        #[serde(default)]
+        pin: Option<Pin>,
+    },
+    /// Ignore triggers for `seconds`, then re-arm. Requires the PIN.
+    Pause {
+        seconds: u64,
+        #[serde(default)]
+        pin: Option<Pin>,
+    },
+    Fire,
+    Reload,
+}
+
+/// The single-line JSON reply to a `Request`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Self { ok: true, ..Default::default() }
+    }
+
+    pub fn error(msg: impl Into<String>) -> Self {
+        Self { ok: false, error: Some(msg.into()), ..Default::default() }
+    }
+
+    pub fn status(status: Status) -> Self {
+        Self { ok: true, status: Some(status), ..Default::default() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub hostname: String,
+    /// `arming`, `armed`, `disarmed`, `paused` or `triggered`
+    pub state: String,
+    /// End of the grace period or pause
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+    pub triggers: Vec<TriggerStatus>,
+    /// Seconds until the heartbeat timer fires, if it is running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heartbeat_remaining: Option<u64>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "host: {}", self.hostname)?;
+        match &self.until {
+            Some(until) => writeln!(f, "state: {} until {}", self.state, until)?,
+            None => writeln!(f, "state: {}", self.state)?,
+        }
+        for trigger in &self.triggers {
+            match trigger.remaining {
+                Some(secs) => writeln!(f, "{:<10} {:<10} {}s remaining", trigger.name, trigger.state, secs)?,
+                None => writeln!(f, "{:<10} {}", trigger.name, trigger.state)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TriggerStatus {
+    pub name: String,
+    /// `up`, `starting`, `restarting`, `failed`, `disarmed` or `off-schedule`
+    pub state: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+}
+
+/// The caller, from the socket's peer credentials.
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+    pub uid: u32,
+    pub pid: Option<i32>,
+}
+
+/// A request accepted by the `ControlServer`, to be answered with `respond`.
+pub struct ControlRequest {
+    pub request: Request,
+    pub peer: Peer,
+    reply: oneshot::Sender<Response>,
+}
+
+impl ControlRequest {
+    pub fn respond(self, response: Response) {
+        let _ = self.reply.send(response);
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/dms.sock`, else `/run/dms.sock` for root and
+/// `/tmp/dms-<uid>/dms.sock` (a private directory) for everyone else.
+pub fn default_socket_path() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+        return Path::new(&dir).join("dms.sock");
+    }
+
+    #[cfg(unix)]
+    {
+        match euid() {
+            0 => PathBuf::from("/run/dms.sock"),
+            uid => std::env::temp_dir().join(format!("dms-{}", uid)).join("dms.sock"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    std::env::temp_dir().join("dms.sock")
+}
+
+#[cfg(unix)]
+pub(crate) fn euid() -> u32 {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() }
+}
+
+/// Listens on the control socket and hands authenticated requests to `recv`.
+/// Only the user the switch runs as (and root) may connect: the socket is
+/// created with mode 0600 in a directory no one else can write to, and
+/// every peer's uid is checked.
+pub struct ControlServer {
+    requests: Option<mpsc::Receiver<ControlRequest>>,
+    #[cfg(unix)]
+    listener: Option<(PathBuf, tokio::task::JoinHandle<()>)>,
+}
+
+impl ControlServer {
+    /// A server that never yields a request.
+    pub fn disabled() -> Self {
+        Self {
+            requests: None,
+            #[cfg(unix)]
+            listener: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn bind(config: &ControlConfig) -> Result<Self> {
+        if !config.enabled {
+            return Ok(Self::disabled());
+        }
+
+        let path = config.socket_path();
+        let listener = unix::bind(&path)?;
+        let (tx, rx) = mpsc::channel(8);
+        let task = tokio::spawn(unix::accept_loop(listener, tx));
+
+        log::info!("[+] Control socket: {}", path.display());
+        Ok(Self { requests: Some(rx), listener: Some((path, task)) })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(config: &ControlConfig) -> Result<Self> {
+        if config.enabled {
+            log::warn!("[!] Control socket is only supported on Unix");
+        }
+        Ok(Self::disabled())
+    }
+
+    pub async fn recv(&mut self) -> ControlRequest {
+        match self.requests.as_mut() {
+            Some(rx) => match rx.recv().await {
+                Some(request) => request,
+                None => std::future::pending().await,
+            },
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if let Some((path, task)) = self.listener.take() {
+            task.abort();
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Sends one request to the running instance and waits for the reply.
+/// Nothing is sent unless the socket belongs to us or root.
+#[cfg(unix)]
+pub fn request(path: &Path, request: &Request) -> Result<Response> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)?;
+    let uid = unix::peer_uid(&stream)?;
+    if !unix::trusted(uid) {
+        return Err(DmsError::Control(format!(
+            "socket {} is held by uid {}, not sending the request", path.display(), uid)));
+    }
+    stream.set_read_timeout(Some(unix::IO_TIMEOUT))?;
+
+    let mut line = serde_json::to_string(request)
+        .map_err(|e| DmsError::Control(format!("request: {}", e)))?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    serde_json::from_str(&reply)
+        .map_err(|e| DmsError::Control(format!("invalid reply: {}", e)))
+}
+
+#[cfg(not(unix))]
+pub fn request(_path: &Path, _request: &Request) -> Result<Response> {
+    Err(DmsError::Control("only supported on Unix".into()))
+}
+
+/// `DeadManSwitch ctl` subcommands, one `Request` each.
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum CtlAction {
+    /// Show trigger states and the heartbeat time remaining
+    Status {
+        /// Print the raw JSON reply
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Re-arm the switch, or start a single trigger that was disarmed
+    Arm {
+        #[clap(value_parser = config::parse_trigger_name)]
+        trigger: Option<String>,
+    },
+
+    /// Disarm the switch, or stop a single trigger (asks for the PIN)
+    Disarm {
+        #[clap(value_parser = config::parse_trigger_name)]
+        trigger: Option<String>,
+    },
+
+    /// Ignore triggers for a while, e.g. 90s, 30m or 2h (asks for the PIN)
+    Pause {
+        #[clap(value_parser = parse_duration)]
+        duration: u64,
+    },
+
+    /// Fire the switch
+    Fire,
+
+    /// Reload the config
+    Reload,
+}
+
+impl CtlAction {
+    /// Asks for the PIN where the request needs one.
+    pub fn request(&self) -> Result<Request> {
+        Ok(match self {
+            Self::Status { .. } => Request::Status,
+            Self::Arm { trigger } => Request::Arm { trigger: trigger.clone() },
+            Self::Disarm { trigger } => Request::Disarm { trigger: trigger.clone(), pin: Some(pin::read("PIN: ")?) },
+            Self::Pause { duration } => Request::Pause { seconds: *duration, pin: Some(pin::read("PIN: ")?) },
+            Self::Fire => Request::Fire,
+            Self::Reload => Request::Reload,
+        })
+    }
+}
+
+/// Sends `action` to the running instance and prints the reply.
+pub fn run_ctl(config: &ControlConfig, action: &CtlAction) -> Result<()> {
+    let response = request(&config.socket_path(), &action.request()?)?;
+    if let CtlAction::Status { json: true } = action {
+        println!("{}", serde_json::to_string_pretty(&response).unwrap_or_default());
+    }
+    if !response.ok {
+        return Err(DmsError::Control(response.error.unwrap_or_default()));
+    }
+
+    match (action, response.status) {
+        (CtlAction::Status { json: true }, _) => {}
+        (CtlAction::Status { .. }, Some(status)) => print!("{}", status),
+        _ => println!("ok"),
+    }
+    Ok(())
+}
+
+/// Asks the running instance to reload its config and waits for the result.
+/// SIGHUP reloads too, e.g. from a service manager, but cannot report back.
+pub fn reload(socket: &Path) -> Result<()> {
+    let response = request(socket, &Request::Reload)?;
+    if !response.ok {
+        return Err(DmsError::Control(response.error.unwrap_or_default()));
+    }
+    log::info!("[+] Config reloaded");
+    Ok(())
+}
+
+/// `--trigger` fires the running instance when there is one, so that its
+/// configured actions run instead of a second process's. Whenever that does
+/// not work out the actions run here: a manual trigger never does nothing.
+pub fn fire_running_instance(config: &ControlConfig) -> bool {
+    if !cfg!(unix) || !config.enabled {
+        return false;
+    }
+
+    match request(&config.socket_path(), &Request::Fire) {
+        Ok(response) if response.ok => {
+            log::warn!("[!] Fired the running instance");
+            true
+        }
+        Ok(response) => {
+            log::error!("[!] Running instance refused to fire ({}), running the actions here",
+                        response.error.unwrap_or_default());
+            false
+        }
+        Err(DmsError::Network(e)) if matches!(e.kind(),
+            std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused) => false,
+        Err(e) => {
+            log::error!("[!] Could not fire the running instance ({}), running the actions here", e);
+            false
+        }
+    }
+}
+
+/// Seconds from `90`, `90s`, `30m`, `2h` or `1d`.
+pub fn parse_duration(value: &str) -> std::result::Result<u64, String> {
+    let value = value.trim();
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("invalid duration '{}' (expected e.g. 90s, 30m, 2h)", value)),
+    };
+    digits.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("invalid duration '{}'", value))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use std::path::Path;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::sync::{mpsc, oneshot};
+    use crate::error::{DmsError, Result};
+    use super::{euid, ControlRequest, Peer, Request, Response};
+
+    pub const IO_TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_REQUEST: u64 = 64 * 1024;
+
+    pub fn bind(path: &Path) -> Result<UnixListener> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            private_dir(dir)?;
+        }
+        if path.exists() {
+            // A live socket means another instance; a dead one is stale
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(DmsError::Control(format!(
+                    "socket {} is in use by another instance", path.display())));
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+
+    /// Creates `dir` (mode 0700) if it is missing, then checks it with
+    /// `check_private`.
+    pub fn private_dir(dir: &Path) -> Result<()> {
+        if !dir.exists() {
+            std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        check_private(dir)
+    }
+
+    /// Fails unless `dir` is a real directory of ours that no other user can
+    /// write to, so no one else can have put a socket there.
+    fn check_private(dir: &Path) -> Result<()> {
+        let meta = std::fs::symlink_metadata(dir)?;
+        if !meta.is_dir() {
+            return Err(DmsError::Control(format!("{} is not a directory", dir.display())));
+        }
+        if meta.uid() != euid() {
+            return Err(DmsError::Control(format!(
+                "{} is owned by uid {}, not {}", dir.display(), meta.uid(), euid())));
+        }
+        if meta.mode() & 0o022 != 0 {
+            return Err(DmsError::Control(format!(
+                "{} is writable by other users (mode {:o})", dir.display(), meta.mode() & 0o777)));
+        }
+        Ok(())
+    }
+
+    /// Only the user the switch runs as, and root, may talk to it.
+    pub fn trusted(uid: u32) -> bool {
+        uid == euid() || uid == 0
+    }
+
+    /// The uid of the process at the other end of `stream`.
+    pub fn peer_uid(stream: &std::os::unix::net::UnixStream) -> std::io::Result<u32> {
+        use std::os::unix::io::AsRawFd;
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+            let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+            // SAFETY: cred and len are valid for writes of the sizes given
+            let rc = unsafe {
+                libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED,
+                                 &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+            };
+            if rc != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(cred.uid)
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let (mut uid, mut gid) = (0, 0);
+            // SAFETY: uid and gid are valid for writes
+            if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(uid)
+        }
+    }
+
+    pub async fn accept_loop(listener: UnixListener, requests: mpsc::Sender<ControlRequest>) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("[!] Control socket accept failed: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle(stream, requests).await {
+                    log::error!("[!] Control connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle(stream: UnixStream, requests: mpsc::Sender<ControlRequest>) -> Result<()> {
+        let cred = stream.peer_cred()?;
+        let peer = Peer { uid: cred.uid(), pid: cred.pid() };
+        let (read, mut write) = stream.into_split();
+
+        let response = if !trusted(peer.uid) {
+            log::warn!("[!] Control request from uid {} rejected", peer.uid);
+            Response::error("permission denied")
+        } else {
+            let mut line = String::new();
+            let mut reader = BufReader::new(read.take(MAX_REQUEST));
+            tokio::time::timeout(IO_TIMEOUT, reader.read_line(&mut line)).await
+                .map_err(|_| DmsError::Control("request timed out".into()))??;
+
+            match serde_json::from_str::<Request>(&line) {
+                Ok(request) => dispatch(request, peer, &requests).await,
+                Err(e) => Response::error(format!("invalid request: {}", e)),
+            }
+        };
+
+        let mut reply = serde_json::to_string(&response)
+            .map_err(|e| DmsError::Control(format!("reply: {}", e)))?;
+        reply.push('\n');
+        write.write_all(reply.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn dispatch(request: Request, peer: Peer, requests: &mpsc::Sender<ControlRequest>) -> Response {
+        let (reply, response) = oneshot::channel();
+        if requests.send(ControlRequest { request, peer, reply }).await.is_err() {
+            return Response::error("switch is shutting down");
+        }
+        response.await.unwrap_or_else(|_| Response::error("switch is shutting down"))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dms-control-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn socket_dir_must_be_private() {
+        let dir = scratch("dir");
+        unix::private_dir(&dir.join("run")).unwrap();
+        let mode = std::fs::metadata(dir.join("run")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        std::fs::set_permissions(dir.join("run"), std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(unix::private_dir(&dir.join("run")).is_err());
+
+        std::fs::create_dir(dir.join("real")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        assert!(unix::private_dir(&dir.join("link")).is_err());
+
+        if euid() == 0 {
+            let other = dir.join("other");
+            std::fs::create_dir(&other).unwrap();
+            std::os::unix::fs::chown(&other, Some(65534), None).unwrap();
+            assert!(unix::private_dir(&other).is_err());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn client_checks_peer() {
+        assert!(unix::trusted(euid()));
+        assert!(unix::trusted(0));
+        assert!(!unix::trusted(euid().wrapping_add(1).max(1)));
+
+        let dir = scratch("peer");
+        unix::private_dir(&dir).unwrap();
+        let path = dir.join("dms.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            use std::io::{BufRead, BufReader, Write};
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(unix::peer_uid(&stream).unwrap(), euid());
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            stream.write_all(b"{\"ok\":true}\n").unwrap();
+        });
+
+        assert!(request(&path, &Request::Status).unwrap().ok);
+        server.join().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(1800));
+        assert_eq!(parse_duration(" 2h "), Ok(7200));
+        assert_eq!(parse_duration("1d"), Ok(86400));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+}
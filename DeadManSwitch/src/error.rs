@@ -11,7 +11,6 @@ pub enum DmsError {
     #[error("USB error: {0}")]
     Usb(#[from] rusb::Error),
     
-    #[allow(dead_code)]
     #[error("Telegram error: {0}")]
     Telegram(String),
     
@@ -20,6 +19,15 @@ pub enum DmsError {
     
     #[error("Task join error: {0}")]
     Join(String),
+    
+    #[error("Control error: {0}")]
+    Control(String),
+
+    #[error("Audit error: {0}")]
+    Audit(String),
+
+    #[error("Logging error: {0}")]
+    Logging(String),
 }
 
 pub type Result<T> = std::result::Result<T, DmsError>;
\ No newline at end of file
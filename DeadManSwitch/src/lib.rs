@@ -0,0 +1,22 @@
+//! Dead Man Switch as a library: config loading, triggers and the trigger
+//! channel, the lockdown actions and the policy that picks them. The
+//! `DeadManSwitch` binary is a thin CLI on top of [`switch::run_monitors`].
+
+pub mod actions;
+pub mod audit;
+pub mod check;
+pub mod config;
+pub mod control;
+pub mod error;
+pub mod logging;
+pub mod luks;
+pub mod pin;
+pub mod policy;
+pub mod rules;
+pub mod schedule;
+pub mod secret;
+pub mod state;
+pub mod switch;
+pub mod triggers;
+pub mod ui;
+pub mod veracrypt;
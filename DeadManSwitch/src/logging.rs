@@ -0,0 +1,555 @@
+use chrono::{Local, SecondsFormat, Utc};
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{ColorChoice, TermLogger, TerminalMode};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use crate::config::{FileLogConfig, JournaldConfig, LoggingConfig, SyslogConfig};
+use crate::error::{DmsError, Result};
+use crate::triggers::hostname;
+
+static SINKS: RwLock<Vec<Sink>> = RwLock::new(Vec::new());
+/// Smallest `max_size` other than 0; below it the file would rotate on
+/// nearly every line.
+const MIN_FILE_SIZE: u64 = 4096;
+
+/// A logger and the most verbose level it receives.
+struct Sink {
+    level: LevelFilter,
+    logger: Box<dyn Log>,
+}
+
+/// Hands each record to every sink whose level admits it.
+struct Dispatch;
+
+impl Log for Dispatch {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let sinks = SINKS.read().unwrap_or_else(|e| e.into_inner());
+        sinks.iter().any(|s| metadata.level() <= s.level)
+    }
+
+    fn log(&self, record: &Record) {
+        deliver(&SINKS.read().unwrap_or_else(|e| e.into_inner()), record);
+    }
+
+    fn flush(&self) {
+        let sinks = SINKS.read().unwrap_or_else(|e| e.into_inner());
+        for sink in sinks.iter() {
+            sink.logger.flush();
+        }
+    }
+}
+
+fn deliver(sinks: &[Sink], record: &Record) {
+    for sink in sinks.iter().filter(|s| record.level() <= s.level) {
+        sink.logger.log(record);
+    }
+}
+
+/// Installs the logger with the terminal at `info`. `configure` then applies
+/// the `[logging]` section.
+pub fn init() {
+    install(vec![terminal(LevelFilter::Info)]);
+    let _ = log::set_logger(&Dispatch);
+}
+
+/// Replaces the sinks with the configured ones. A sink that cannot be opened
+/// is logged and left out; logging never stops the switch.
+pub fn configure(config: &LoggingConfig) {
+    let mut sinks = vec![terminal(config.level.into())];
+    let mut errors = Vec::new();
+
+    if config.file.enabled {
+        match FileSink::open(&config.file) {
+            Ok(file) => sinks.push(Sink { level: config.file.level.into(), logger: Box::new(file) }),
+            Err(e) => errors.push(format!("file {}: {}", config.file.path().display(), e)),
+        }
+    }
+    if config.syslog.enabled {
+        match SyslogSink::open(&config.syslog) {
+            Ok(syslog) => sinks.push(Sink { level: config.syslog.level.into(), logger: Box::new(syslog) }),
+            Err(e) => errors.push(format!("syslog {}: {}", config.syslog.target, e)),
+        }
+    }
+    if config.journald.enabled {
+        match journald(&config.journald) {
+            Ok(sink) => sinks.push(sink),
+            Err(e) => errors.push(format!("journald: {}", e)),
+        }
+    }
+
+    install(sinks);
+    for error in errors {
+        log::error!("[!] Log sink unavailable: {}", error);
+    }
+}
+
+/// Rejects a log file `max_size` too small to rotate sensibly, unknown
+/// syslog facilities and targets, and journald off Linux.
+pub fn validate(config: &LoggingConfig) -> Result<()> {
+    let invalid = |key: &str, msg: String| DmsError::Config(format!("logging.{}: {}", key, msg));
+
+    let max_size = config.file.max_size;
+    if max_size > 0 && max_size < MIN_FILE_SIZE {
+        return Err(invalid("file.max_size", format!("must be 0 (no rotation) or at least {} bytes", MIN_FILE_SIZE)));
+    }
+
+    facility(&config.syslog.facility).map_err(|e| invalid("syslog.facility", e))?;
+    Target::parse(&config.syslog.target).map_err(|e| invalid("syslog.target", e))?;
+    if config.syslog.app_name.is_empty() || config.syslog.app_name.contains(char::is_whitespace) {
+        return Err(invalid("syslog.app_name", "must be a single word".into()));
+    }
+    if config.journald.enabled && !cfg!(target_os = "linux") {
+        return Err(invalid("journald", "only available on Linux".into()));
+    }
+    Ok(())
+}
+
+/// `dms.log` next to the audit log.
+pub fn default_path() -> PathBuf {
+    crate::audit::default_path().with_file_name("dms.log")
+}
+
+fn install(sinks: Vec<Sink>) {
+    let max = sinks.iter().map(|s| s.level).max().unwrap_or(LevelFilter::Off);
+    *SINKS.write().unwrap_or_else(|e| e.into_inner()) = sinks;
+    log::set_max_level(max);
+}
+
+fn terminal(level: LevelFilter) -> Sink {
+    let logger = TermLogger::new(level, simplelog::Config::default(), TerminalMode::Mixed, ColorChoice::Auto);
+    Sink { level, logger }
+}
+
+/// The record's key-values, e.g. `trigger = "usb"` in
+/// `log::warn!(trigger = "usb"; "...")`.
+fn fields(record: &Record) -> Vec<(String, String)> {
+    struct Collect(Vec<(String, String)>);
+
+    impl<'kvs> VisitSource<'kvs> for Collect {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> std::result::Result<(), log::kv::Error> {
+            self.0.push((key.to_string(), value.to_string()));
+            Ok(())
+        }
+    }
+
+    let mut collect = Collect(Vec::new());
+    let _ = record.key_values().visit(&mut collect);
+    collect.0
+}
+
+/// Syslog severity.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Plain text log file, rotated by size.
+struct FileSink {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    file: Mutex<(File, u64)>,
+}
+
+impl FileSink {
+    fn open(config: &FileLogConfig) -> std::io::Result<Self> {
+        let path = config.path();
+        let file = open_log(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_size: config.max_size, keep: config.keep, file: Mutex::new((file, size)) })
+    }
+
+    /// `<path>` becomes `<path>.1`, `<path>.1` becomes `<path>.2`, and so on;
+    /// the oldest falls off. With `keep = 0` the file is just emptied.
+    fn rotate(&self, current: &mut (File, u64)) -> std::io::Result<()> {
+        if self.keep == 0 {
+            current.0.set_len(0)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                let _ = std::fs::rename(rotated(&self.path, i), rotated(&self.path, i + 1));
+            }
+            std::fs::rename(&self.path, rotated(&self.path, 1))?;
+            current.0 = open_log(&self.path)?;
+        }
+        current.1 = 0;
+        Ok(())
+    }
+}
+
+impl Log for FileSink {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let line = file_line(record);
+        let mut current = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if self.max_size > 0 && current.1 > 0 && current.1 + line.len() as u64 > self.max_size {
+            // Keep writing to the old file rather than lose the line
+            let _ = self.rotate(&mut current);
+        }
+        if current.0.write_all(line.as_bytes()).is_ok() {
+            current.1 += line.len() as u64;
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap_or_else(|e| e.into_inner()).0.flush();
+    }
+}
+
+/// `<local time> [LEVEL] message key=value ...`
+fn file_line(record: &Record) -> String {
+    let mut line = format!("{} [{}] {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), record.level(), record.args());
+    for (key, value) in fields(record) {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line.push('\n');
+    line
+}
+
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn open_log(path: &Path) -> std::io::Result<File> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty() && !d.exists()) {
+        std::fs::create_dir_all(dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+    }
+
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+enum Target {
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Unix(PathBuf),
+    Udp(String),
+}
+
+impl Target {
+    fn parse(target: &str) -> std::result::Result<Self, String> {
+        match target.split_once(':') {
+            #[cfg(unix)]
+            Some(("unix", path)) if !path.is_empty() => Ok(Target::Unix(PathBuf::from(path))),
+            #[cfg(not(unix))]
+            Some(("unix", _)) => Err("unix sockets are not available on this platform".into()),
+            Some(("udp", host)) if !host.is_empty() => Ok(Target::Udp(host.to_string())),
+            _ => Err(format!("'{}': expected unix:<socket> or udp:<host>[:<port>]", target)),
+        }
+    }
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram, PathBuf),
+    Udp(UdpSocket),
+}
+
+/// RFC 5424 messages; key-values go into a `dms@32473` structured data
+/// element.
+struct SyslogSink {
+    transport: Transport,
+    facility: u8,
+    app_name: String,
+    hostname: String,
+}
+
+impl SyslogSink {
+    fn open(config: &SyslogConfig) -> std::io::Result<Self> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+        let transport = match Target::parse(&config.target).map_err(invalid)? {
+            #[cfg(unix)]
+            Target::Unix(path) => Transport::Unix(std::os::unix::net::UnixDatagram::unbound()?, path),
+            #[cfg(not(unix))]
+            Target::Unix(_) => unreachable!(),
+            Target::Udp(host) => {
+                let addr = resolve(&host)?;
+                let local: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(addr)?;
+                Transport::Udp(socket)
+            }
+        };
+
+        Ok(Self {
+            transport,
+            facility: facility(&config.facility).map_err(invalid)?,
+            app_name: config.app_name.clone(),
+            hostname: hostname(),
+        })
+    }
+}
+
+impl Log for SyslogSink {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let message = syslog_message(self.facility, &self.hostname, &self.app_name, record);
+        // Nowhere to report a failure to log
+        let _ = match &self.transport {
+            #[cfg(unix)]
+            Transport::Unix(socket, path) => socket.send_to(message.as_bytes(), path),
+            Transport::Udp(socket) => socket.send(message.as_bytes()),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+/// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID - STRUCTURED-DATA MSG`
+fn syslog_message(facility: u8, hostname: &str, app_name: &str, record: &Record) -> String {
+    let fields = fields(record);
+    let data = if fields.is_empty() {
+        "-".to_string()
+    } else {
+        let params: String = fields.iter()
+            .map(|(key, value)| format!(" {}=\"{}\"", key, escape_param(value)))
+            .collect();
+        format!("[dms@32473{}]", params)
+    };
+
+    format!("<{}>1 {} {} {} {} - {} {}",
+        facility * 8 + severity(record.level()),
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        hostname, app_name, std::process::id(), data, record.args())
+}
+
+/// `host:port`, or `host` on the default syslog port.
+fn resolve(host: &str) -> std::io::Result<SocketAddr> {
+    host.to_socket_addrs()
+        .or_else(|_| (host, 514).to_socket_addrs())?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "host has no address"))
+}
+
+fn escape_param(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+fn facility(name: &str) -> std::result::Result<u8, String> {
+    const NAMES: [&str; 12] = [
+        "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp",
+    ];
+    if let Some(code) = NAMES.iter().position(|n| *n == name) {
+        return Ok(code as u8);
+    }
+    match name.strip_prefix("local").and_then(|n| n.parse::<u8>().ok()) {
+        Some(n) if n <= 7 => Ok(16 + n),
+        _ => Err(format!("unknown facility '{}'", name)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn journald(config: &JournaldConfig) -> std::io::Result<Sink> {
+    Ok(Sink { level: config.level.into(), logger: Box::new(journald::JournaldSink::open()?) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn journald(_: &JournaldConfig) -> std::io::Result<Sink> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+mod journald {
+    use log::{Log, Metadata, Record};
+    use std::os::unix::net::UnixDatagram;
+    use std::path::Path;
+
+    const SOCKET: &str = "/run/systemd/journal/socket";
+
+    /// The journal's native protocol; key-values become `DMS_<KEY>` fields.
+    pub(super) struct JournaldSink {
+        socket: UnixDatagram,
+    }
+
+    impl JournaldSink {
+        pub(super) fn open() -> std::io::Result<Self> {
+            if !Path::new(SOCKET).exists() {
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} not found", SOCKET)));
+            }
+            Ok(Self { socket: UnixDatagram::unbound()? })
+        }
+    }
+
+    impl Log for JournaldSink {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let mut payload = Vec::new();
+            field(&mut payload, "PRIORITY", &super::severity(record.level()).to_string());
+            field(&mut payload, "MESSAGE", &record.args().to_string());
+            field(&mut payload, "SYSLOG_IDENTIFIER", "dms");
+            if let Some(module) = record.module_path() {
+                field(&mut payload, "CODE_MODULE", module);
+            }
+            if let Some(file) = record.file() {
+                field(&mut payload, "CODE_FILE", file);
+            }
+            if let Some(line) = record.line() {
+                field(&mut payload, "CODE_LINE", &line.to_string());
+            }
+            for (key, value) in super::fields(record) {
+                let name: String = key.chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                    .collect();
+                field(&mut payload, &format!("DMS_{}", name), &value);
+            }
+            let _ = self.socket.send_to(&payload, SOCKET);
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// `NAME=value`, or the length-prefixed form for values with newlines.
+    fn field(payload: &mut Vec<u8>, name: &str, value: &str) {
+        payload.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            payload.push(b'\n');
+            payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            payload.push(b'=');
+        }
+        payload.extend_from_slice(value.as_bytes());
+        payload.push(b'\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LogLevel;
+    use std::sync::Arc;
+
+    /// Keeps the messages it is handed.
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dms-logging-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(sink: &FileSink, n: u32) {
+        sink.log(&Record::builder().level(Level::Info).args(format_args!("line {:02}", n)).build());
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path).unwrap_or_default()
+            .lines()
+            .map(|l| l.rsplit("] ").next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sinks_filter_by_level() {
+        let warn: Arc<Mutex<Vec<String>>> = Arc::default();
+        let debug: Arc<Mutex<Vec<String>>> = Arc::default();
+        let sinks = vec![
+            Sink { level: LevelFilter::Warn, logger: Box::new(Capture(Arc::clone(&warn))) },
+            Sink { level: LevelFilter::Debug, logger: Box::new(Capture(Arc::clone(&debug))) },
+        ];
+        for level in [Level::Error, Level::Info, Level::Trace] {
+            deliver(&sinks, &Record::builder().level(level).args(format_args!("{}", level)).build());
+        }
+        assert_eq!(*warn.lock().unwrap(), ["ERROR"]);
+        assert_eq!(*debug.lock().unwrap(), ["ERROR", "INFO"]);
+    }
+
+    #[test]
+    fn formats_rfc5424() {
+        let kvs = [("trigger", "usb"), ("note", "a \"b\" ]")];
+        let record = Record::builder().level(Level::Warn).args(format_args!("Trigger")).key_values(&kvs).build();
+        let message = syslog_message(3, "host", "dms", &record);
+        let parts: Vec<&str> = message.splitn(7, ' ').collect();
+        assert_eq!(parts[0], "<28>1");
+        assert!(chrono::DateTime::parse_from_rfc3339(parts[1]).is_ok(), "{}", parts[1]);
+        assert_eq!(parts[2..6], ["host", "dms", &std::process::id().to_string(), "-"]);
+        assert_eq!(parts[6], r#"[dms@32473 trigger="usb" note="a \"b\" \]"] Trigger"#);
+
+        let record = Record::builder().level(Level::Info).args(format_args!("Plain")).build();
+        let message = syslog_message(1, "host", "dms", &record);
+        assert!(message.starts_with("<14>1 ") && message.ends_with(" - - Plain"), "{}", message);
+    }
+
+    #[test]
+    fn rotates_and_keeps() {
+        let dir = scratch("rotate");
+        let path = dir.join("dms.log");
+        let open = |max_size, keep| FileSink::open(&FileLogConfig {
+            enabled: true, path: Some(path.clone()), level: LogLevel::Info, max_size, keep,
+        }).unwrap();
+        let line = file_line(&Record::builder().args(format_args!("line 00")).level(Level::Info).build()).len() as u64;
+
+        // Three lines per file, two rotated files
+        let sink = open(3 * line, 2);
+        (0..10).for_each(|n| write(&sink, n));
+        assert_eq!(lines(&path), ["line 09"]);
+        assert_eq!(lines(&rotated(&path, 1)), ["line 06", "line 07", "line 08"]);
+        assert_eq!(lines(&rotated(&path, 2)), ["line 03", "line 04", "line 05"]);
+        assert!(!rotated(&path, 3).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let sink = open(3 * line, 0);
+        (0..4).for_each(|n| write(&sink, n));
+        assert_eq!(lines(&path), ["line 03"]);
+        assert!(!rotated(&path, 1).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let sink = open(0, 2);
+        (0..10).for_each(|n| write(&sink, n));
+        assert_eq!(lines(&path).len(), 10);
+        assert!(!rotated(&path, 1).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rejects_tiny_max_size() {
+        let mut config = LoggingConfig::default();
+        for (max_size, ok) in [(0, true), (1, false), (MIN_FILE_SIZE - 1, false), (MIN_FILE_SIZE, true)] {
+            config.file.max_size = max_size;
+            assert_eq!(validate(&config).is_ok(), ok, "max_size = {}", max_size);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::actions::ActionExecutor;
+
+const SYS_BLOCK: &str = "/sys/block";
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// A device-mapper device as seen in `/sys/block/dm-*`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DmDevice {
+    /// Kernel name, e.g. `dm-0`
+    pub kernel: String,
+    /// Mapping name, e.g. `luks-home`
+    pub name: String,
+    /// `major:minor`
+    pub dev: String,
+    /// A dm-crypt mapping (LUKS or plain)
+    pub crypt: bool,
+    /// Kernel names of devices stacked on top, e.g. LVM volumes
+    pub holders: Vec<String>,
+}
+
+/// One command of a close, in the order they must run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Unmount(PathBuf),
+    /// `cryptsetup close`
+    Close(String),
+    /// `dmsetup remove`, for non-crypt devices stacked on a mapping
+    Remove(String),
+}
+
+impl Step {
+    pub fn command(&self) -> Vec<String> {
+        match self {
+            Step::Unmount(path) => vec!["umount".into(), path.display().to_string()],
+            Step::Close(name) => vec!["cryptsetup".into(), "close".into(), name.clone()],
+            Step::Remove(name) => vec!["dmsetup".into(), "remove".into(), name.clone()],
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+    /// Targeted mappings that will be closed
+    pub targets: Vec<String>,
+    /// Mappings under the root filesystem, which only the shutdown can close
+    pub skipped: Vec<String>,
+}
+
+/// Every device-mapper device under `sys_block`, ordered by kernel name.
+pub fn dm_devices(sys_block: &Path) -> Vec<DmDevice> {
+    let Ok(entries) = fs::read_dir(sys_block) else {
+        return vec![];
+    };
+    let read = |path: PathBuf| fs::read_to_string(path).map(|s| s.trim().to_string()).unwrap_or_default();
+
+    let mut devices: Vec<DmDevice> = entries.flatten()
+        .filter_map(|entry| {
+            let kernel = entry.file_name().to_string_lossy().into_owned();
+            let dir = entry.path();
+            let name = read(dir.join("dm").join("name"));
+            if !kernel.starts_with("dm-") || name.is_empty() {
+                return None;
+            }
+            let holders = fs::read_dir(dir.join("holders")).into_iter().flatten().flatten()
+                .map(|h| h.file_name().to_string_lossy().into_owned())
+                .collect();
+            Some(DmDevice {
+                crypt: read(dir.join("dm").join("uuid")).starts_with("CRYPT-"),
+                dev: read(dir.join("dev")),
+                kernel,
+                name,
+                holders,
+            })
+        })
+        .collect();
+    devices.sort_by(|a, b| a.kernel.cmp(&b.kernel));
+    devices
+}
+
+/// The steps that close the `selected` crypt mappings, or every one if none
+/// are selected: first unmount everything mounted on them, on devices
+/// stacked on them, or below those mount points (innermost first), then
+/// remove the stacked devices and close the mappings, top-most first.
+/// Selected mappings that are not open are left out.
+pub fn plan(devices: &[DmDevice], mountinfo: &str, selected: &[String]) -> Plan {
+    let mounts = parse_mountinfo(mountinfo);
+    let mut plan = Plan::default();
+
+    // Holders before the devices they sit on
+    let mut order: Vec<&DmDevice> = Vec::new();
+    let mut seen = HashSet::new();
+    for target in devices.iter().filter(|d| d.crypt && (selected.is_empty() || selected.contains(&d.name))) {
+        let mut stack = Vec::new();
+        stacked(target, devices, &mut seen, &mut stack);
+        let holds_root = stack.iter()
+            .any(|d| mounts.iter().any(|(dev, path)| *dev == d.dev && path == Path::new("/")));
+        if holds_root {
+            plan.skipped.push(target.name.clone());
+        } else {
+            plan.targets.push(target.name.clone());
+            order.extend(stack);
+        }
+    }
+
+    let numbers: HashSet<&str> = order.iter().map(|d| d.dev.as_str()).collect();
+    let roots: Vec<&PathBuf> = mounts.iter()
+        .filter(|(dev, _)| numbers.contains(dev.as_str()))
+        .map(|(_, path)| path)
+        .collect();
+
+    // Later mounts sit on top of earlier ones
+    plan.steps = mounts.iter().rev()
+        .filter(|(_, path)| roots.iter().any(|root| path.starts_with(root)))
+        .map(|(_, path)| Step::Unmount(path.clone()))
+        .collect();
+    plan.steps.extend(order.iter().map(|d| match d.crypt {
+        true => Step::Close(d.name.clone()),
+        false => Step::Remove(d.name.clone()),
+    }));
+    plan
+}
+
+/// `device` and everything stacked on it, holders first.
+fn stacked<'a>(device: &'a DmDevice, devices: &'a [DmDevice], seen: &mut HashSet<&'a str>, out: &mut Vec<&'a DmDevice>) {
+    if !seen.insert(&device.kernel) {
+        return;
+    }
+    for holder in devices.iter().filter(|d| device.holders.contains(&d.kernel)) {
+        stacked(holder, devices, seen, out);
+    }
+    out.push(device);
+}
+
+/// `plan` for this machine's mappings and mounts.
+pub fn live_plan(selected: &[String]) -> Plan {
+    let mountinfo = fs::read_to_string(MOUNTINFO).unwrap_or_default();
+    plan(&dm_devices(Path::new(SYS_BLOCK)), &mountinfo, selected)
+}
+
+/// Runs `live_plan`, then reports which of the targeted mappings were
+/// closed. Fails with the ones that are still open.
+pub fn close(selected: &[String], timeout: Duration) -> std::result::Result<Vec<String>, String> {
+    let deadline = Instant::now() + timeout;
+    let plan = live_plan(selected);
+    for name in selected.iter().filter(|n| !plan.targets.contains(n) && !plan.skipped.contains(n)) {
+        log::info!("[+] LUKS mapping {} is not open", name);
+    }
+    for name in &plan.skipped {
+        log::warn!("[!] LUKS mapping {} holds the root filesystem, left to the shutdown", name);
+    }
+
+    for step in plan.steps {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            log::error!("[!] LUKS close timed out after {}s", timeout.as_secs());
+            break;
+        }
+        let command = step.command();
+        let args: Vec<&str> = command[1..].iter().map(String::as_str).collect();
+        log::info!("[+] Running: {}", command.join(" "));
+        // Keep going: a later step may still close other mappings
+        if let Err(e) = ActionExecutor::run_command(&command[0], &args, remaining) {
+            log::error!("[!] {} failed: {}", command.join(" "), e);
+        }
+    }
+
+    let after = dm_devices(Path::new(SYS_BLOCK));
+    let still_open: Vec<String> = plan.targets.iter()
+        .filter(|name| after.iter().any(|d| d.crypt && d.name == **name))
+        .cloned()
+        .collect();
+    if still_open.is_empty() {
+        Ok(plan.targets)
+    } else {
+        Err(format!("still open: {}", still_open.join(", ")))
+    }
+}
+
+/// `(major:minor, mount point)` per line of a `mountinfo` file.
+fn parse_mountinfo(content: &str) -> Vec<(String, PathBuf)> {
+    content.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let dev = fields.nth(2)?;
+            let mount_point = fields.nth(1)?;
+            Some((dev.to_string(), PathBuf::from(unescape(mount_point))))
+        })
+        .collect()
+}
+
+/// Undoes the kernel's octal escapes (`\040` for a space).
+pub(crate) fn unescape(field: &str) -> String {
+    let mut out = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u8::from_str_radix(d, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fresh directory per call: the tests run in parallel.
+    fn scratch(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("dms-luks-{}-{}-{}", name, std::process::id(), n));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn fake_dm(sys: &Path, kernel: &str, name: &str, uuid: &str, dev: &str, holders: &[&str]) {
+        let dir = sys.join(kernel);
+        fs::create_dir_all(dir.join("dm")).unwrap();
+        fs::create_dir_all(dir.join("holders")).unwrap();
+        fs::write(dir.join("dm").join("name"), format!("{}\n", name)).unwrap();
+        fs::write(dir.join("dm").join("uuid"), format!("{}\n", uuid)).unwrap();
+        fs::write(dir.join("dev"), format!("{}\n", dev)).unwrap();
+        for holder in holders {
+            fs::write(dir.join("holders").join(holder), "").unwrap();
+        }
+    }
+
+    // LUKS root, LVM on LUKS for /home, a plain dm-crypt backup disk, and
+    // an unrelated LV
+    const MOUNTINFO: &str = "\
+22 1 253:4 / / rw - ext4 /dev/mapper/luks-sys rw
+30 22 253:1 / /home rw - ext4 /dev/mapper/vg-home rw
+31 30 0:50 / /home/me/.cache rw - tmpfs tmpfs rw
+32 22 253:2 / /mnt/back\\040up rw - ext4 /dev/mapper/backup rw
+33 22 253:3 / /srv rw - ext4 /dev/mapper/vg-srv rw
+";
+
+    fn devices() -> Vec<DmDevice> {
+        let sys = scratch("sys");
+        fake_dm(&sys, "dm-0", "luks-main", "CRYPT-LUKS2-0123-luks-main", "253:0", &["dm-1"]);
+        fake_dm(&sys, "dm-1", "vg-home", "LVM-abc", "253:1", &[]);
+        fake_dm(&sys, "dm-2", "backup", "CRYPT-PLAIN-backup", "253:2", &[]);
+        fake_dm(&sys, "dm-3", "vg-srv", "LVM-def", "253:3", &[]);
+        fake_dm(&sys, "dm-4", "luks-sys", "CRYPT-LUKS2-4567-luks-sys", "253:4", &[]);
+        fs::create_dir_all(sys.join("sda")).unwrap();
+        let devices = dm_devices(&sys);
+        fs::remove_dir_all(&sys).unwrap();
+        devices
+    }
+
+    #[test]
+    fn reads_sysfs() {
+        let devices = devices();
+        assert_eq!(devices.len(), 5);
+        assert_eq!(devices[0].name, "luks-main");
+        assert!(devices[0].crypt && !devices[1].crypt);
+        assert_eq!(devices[0].holders, vec!["dm-1".to_string()]);
+        assert_eq!(devices[2].dev, "253:2");
+    }
+
+    #[test]
+    fn plans_all_in_dependency_order() {
+        let plan = plan(&devices(), MOUNTINFO, &[]);
+        assert_eq!(plan.steps, vec![
+            Step::Unmount("/mnt/back up".into()),
+            Step::Unmount("/home/me/.cache".into()),
+            Step::Unmount("/home".into()),
+            Step::Remove("vg-home".into()),
+            Step::Close("luks-main".into()),
+            Step::Close("backup".into()),
+        ]);
+        assert_eq!(plan.targets, vec!["luks-main".to_string(), "backup".to_string()]);
+        assert_eq!(plan.skipped, vec!["luks-sys".to_string()]);
+    }
+
+    #[test]
+    fn plans_selected_mappings_only() {
+        let plan = plan(&devices(), MOUNTINFO, &["backup".to_string(), "gone".to_string()]);
+        assert_eq!(plan.steps, vec![Step::Unmount("/mnt/back up".into()), Step::Close("backup".into())]);
+        assert_eq!(plan.targets, vec!["backup".to_string()]);
+        assert_eq!(super::plan(&devices(), MOUNTINFO, &["vg-srv".to_string()]), Plan::default());
+    }
+
+    /// Run as root with `cargo test -- --ignored`.
+    #[test]
+    #[ignore = "needs root, cryptsetup and a loop device"]
+    fn closes_loop_backed_luks() {
+        let dir = scratch("loop");
+        let (image, key, mnt) = (dir.join("luks.img"), dir.join("key"), dir.join("mnt"));
+        fs::write(&key, "dms-test-key").unwrap();
+        fs::File::create(&image).unwrap().set_len(32 << 20).unwrap();
+        fs::create_dir(&mnt).unwrap();
+        let name = format!("dms-test-{}", std::process::id());
+        let mapper = format!("/dev/mapper/{}", name);
+        let (image, key, mnt) = (image.to_str().unwrap(), key.to_str().unwrap(), mnt.to_str().unwrap());
+
+        let run = |program: &str, args: &[&str]| {
+            let status = Command::new(program).args(args).status().unwrap();
+            assert!(status.success(), "{} {:?}: {}", program, args, status);
+        };
+        let output = Command::new("losetup").args(["--find", "--show", image]).output().unwrap();
+        let loop_dev = String::from_utf8(output.stdout).unwrap().trim().to_string();
+        run("cryptsetup", &["luksFormat", "--batch-mode", "--pbkdf", "pbkdf2",
+                            "--pbkdf-force-iterations", "1000", "--key-file", key, &loop_dev]);
+        run("cryptsetup", &["open", "--key-file", key, &loop_dev, &name]);
+        run("mkfs.ext4", &["-q", &mapper]);
+        run("mount", &[&mapper, mnt]);
+
+        let result = close(std::slice::from_ref(&name), Duration::from_secs(30));
+        let _ = Command::new("losetup").args(["--detach", &loop_dev]).status();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(result, Ok(vec![name]));
+        assert!(!Path::new(&mapper).exists());
+    }
+}
@@ -1,145 +1,181 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-mod config;
-mod error;
-mod triggers;
-mod actions;
-mod ui;
-
-use clap::Parser;
-use simplelog::*;
+use clap::{Parser, Subcommand};
+use dms::{audit, check, config, control, logging, switch, ui};
+use dms::control::CtlAction;
+use dms::pin;
+use std::path::PathBuf;
 use std::process;
-use std::sync::{Arc, Mutex};
-use crate::error::Result;
-use crate::triggers::*;
-use crate::actions::ActionExecutor;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::mpsc;
+use dms::error::Result;
+use dms::triggers::{TriggerEvent, TriggerRegistry, TriggerSource};
+use dms::actions::{ActionExecutor, Activation, ProgressReceiver};
 
 #[derive(Parser)]
 struct Args {
-    #[clap(short, long, default_value = "all")]
-    mode: String,
-    
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to the TOML config file
+    #[clap(short, long, env = "DMS_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the effective config (secrets redacted) and exit
+    #[clap(long)]
+    print_config: bool,
+
+    #[clap(flatten)]
+    overrides: config::Overrides,
+
     #[clap(short, long)]
     trigger: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Validate config and action prerequisites without arming
+    Check {
+        /// Also probe the Flic server
+        #[clap(long)]
+        probe_flic: bool,
+
+        /// Also probe the Telegram bot token
+        #[clap(long)]
+        probe_telegram: bool,
+    },
+
+    /// Ask the running instance to reload its config, over the control socket
+    Reload,
+
+    /// Print the Argon2 hash of a PIN for `arming.pin_hash`
+    HashPin,
+
+    /// Control the running instance over its local socket
+    #[command(alias = "dmsctl")]
+    Ctl {
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+
+    /// Inspect the audit log
+    Audit {
+        #[command(subcommand)]
+        action: AuditAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuditAction {
+    /// Check the hash chain for tampering or truncation
+    Verify {
+        /// Log to check instead of the configured one
+        #[clap(long)]
+        path: Option<PathBuf>,
+    },
+}
+
 fn main() -> Result<()> {
-    CombinedLogger::init(vec![
-        TermLogger::new(
-            LevelFilter::Info, 
-            simplelog::Config::default(),
-            TerminalMode::Mixed, 
-            ColorChoice::Auto
-        )
-    ]).unwrap();
+    logging::init();
 
     let args = Args::parse();
-    let config = config::Config::default()?;
 
-    if args.trigger {
-        log::warn!("[!] Manual trigger mode");
-        let executor = ActionExecutor::new(config.clone());
-        executor.execute();
-        ui::show_alert();
+    if let Some(Command::HashPin) = args.command {
+        println!("{}", pin::hash(pin::read_new()?.expose())?);
         return Ok(());
     }
 
-    // Flag to signal when to show UI
-    let should_show_ui = Arc::new(Mutex::new(false));
-    let should_show_ui_clone = Arc::clone(&should_show_ui);
-
-    // Spawn async monitors in background thread
-    std::thread::spawn(move || {
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        rt.block_on(async {
-            run_monitors(config, args.mode, should_show_ui_clone).await.ok();
-        });
-    });
-
-    // Main thread waits for trigger signal, then shows UI
-    loop {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        
-        let triggered = *should_show_ui.lock().unwrap();
-        if triggered {
-            ui::show_alert();  // ← UI on main thread
-            process::exit(0);
-        }
+    let source = config::ConfigSource {
+        path: args.config.clone(),
+        overrides: args.overrides.clone(),
+    };
+    let config = source.load();
+
+    if let Some(Command::Reload) = args.command {
+        // The socket is still found when the file to reload is broken; the
+        // running instance then reports why
+        let socket = match &config {
+            Ok(config) => config.control.socket_path(),
+            Err(_) => args.overrides.control_socket.clone().unwrap_or_else(control::default_socket_path),
+        };
+        return control::reload(&socket);
     }
-}
 
-async fn run_monitors(
-    config: config::Config, 
-    mode: String,
-    should_show_ui: Arc<Mutex<bool>>
-) -> Result<()> {
-    let executor = ActionExecutor::new(config.clone());
-    let modes: Vec<_> = mode.split(',').map(str::trim).collect();
-    let run_all = modes.contains(&"all");
-    
-    let (tx, mut rx) = triggers::create_trigger_channel();
-    let mut tasks = vec![];
-    let mut active_modes = vec![];
-
-    if run_all || modes.contains(&"timer") {
-        let timer = timer::HeartbeatTimer::new(config.clone(), tx.clone());
-        let timer_task = tokio::spawn(async move { timer.start().await });
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-
-        if !timer_task.is_finished() {
-            tasks.push(timer_task);
-            active_modes.push("timer");
-        } else {
-            log::warn!("[!] Heartbeat timer skipped");
-        }
+    if let Some(Command::Check { probe_flic, probe_telegram }) = args.command {
+        let config = config.and_then(|c| TriggerRegistry::builtin().validate(&c).map(|()| c));
+        let items = check::run(config, check::CheckOptions { probe_flic, probe_telegram });
+        check::print_table(&items);
+        process::exit(if items.iter().all(|i| i.passed) { 0 } else { 1 });
     }
 
-    if run_all || modes.contains(&"net") {
-        let listener = network::NetworkListener::new(config.clone(), tx.clone());
-        tasks.push(tokio::spawn(async move { listener.start().await }));
-        active_modes.push("net");
+    if let Some(Command::Audit { action: AuditAction::Verify { path } }) = args.command {
+        // A broken config must not stop an investigation
+        let path = path.unwrap_or_else(|| match &config {
+            Ok(config) => config.audit.path(),
+            Err(_) => config::AuditConfig::default().path(),
+        });
+        process::exit(if audit::report(&path) { 0 } else { 1 });
     }
 
-    if run_all || modes.contains(&"bot") {
-        let listener = telegram::TelegramListener::new(config.clone(), tx.clone());
-        let bot_task = tokio::spawn(async move { listener.start().await });
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-        
-        if !bot_task.is_finished() {
-            tasks.push(bot_task);
-            active_modes.push("bot");
-        } else {
-            log::warn!("[!] Telegram skipped");
-        }
-    }
+    let config = config?;
 
-    if run_all || modes.contains(&"usb") {
-        let monitor = usb::UsbMonitor::new(config.clone(), tx.clone());
-        tasks.push(tokio::spawn(async move { monitor.start().await }));
-        active_modes.push("usb");
+    if let Some(Command::Ctl { action }) = &args.command {
+        return control::run_ctl(&config.control, action);
     }
 
-    if run_all || modes.contains(&"flic") {
-        let monitor = flic::FlicMonitor::new(config.clone(), tx.clone());
-        tasks.push(tokio::spawn(async move { monitor.start().await }));
-        active_modes.push("flic");
+    if args.print_config {
+        print!("{}", config.to_redacted_toml()?);
+        return Ok(());
     }
 
-    if active_modes.is_empty() {
-        log::error!("[!] No valid modes");
+    logging::configure(&config.logging);
+    let (progress_tx, progress_rx) = mpsc::channel();
+
+    // A dry run never fires the running instance, which may not be one
+    if args.trigger && !config.dry_run && control::fire_running_instance(&config.control) {
         return Ok(());
     }
 
-    log::info!("[+] DMS armed: {:?}", active_modes);
-    ActionExecutor::send_notification(&active_modes);
-
-    if let Some(event) = rx.recv().await {
-        log::warn!("[!] Trigger from {:?}", event.source);
-        executor.execute();
-        *should_show_ui.lock().unwrap() = true;
-        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-        process::exit(0);
+    if args.trigger {
+        log::warn!("[!] Manual trigger mode");
+        if let Err(e) = audit::open(&config.audit) {
+            log::error!("[!] {}", e);
+        }
+        let executor = ActionExecutor::new(config.clone());
+        executor.execute(&TriggerEvent::new(TriggerSource::Manual), progress_tx);
+    } else {
+        // Monitors run on a background runtime; the UI needs the main thread
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            if let Err(e) = rt.block_on(switch::run_monitors(TriggerRegistry::builtin(), source, config, progress_tx)) {
+                log::error!("[!] {}", e);
+            }
+        });
     }
 
-    Ok(())
-}
\ No newline at end of file
+    let code = handle_activation(progress_rx);
+    audit::flush();
+    process::exit(code);
+}
+
+/// Blocks until a trigger fires and shows the alert while the actions run,
+/// again for every later trigger. Returns the exit code of the last profile
+/// once the switch has stopped and the executor reports it is done.
+fn handle_activation(progress: ProgressReceiver) -> i32 {
+    let Some(activation) = Activation::wait_for_trigger(progress) else {
+        return 1;
+    };
+
+    let activation = Rc::new(RefCell::new(activation));
+    loop {
+        ui::show_alert(Rc::clone(&activation));  // ← UI on main thread
+
+        // The window may close (or fail to open) before the actions finish
+        let completed = activation.borrow_mut().wait();
+        // The switch keeps running after profiles that do not exit
+        if !activation.borrow_mut().next_trigger() {
+            return if completed { 0 } else { 1 };
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
+use crate::error::{DmsError, Result};
+
+/// A PIN as sent over the control socket. Wiped on drop, never printed.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Pin(String);
+
+impl Pin {
+    pub fn new(pin: String) -> Self {
+        Self(pin)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Pin(<redacted>)")
+    }
+}
+
+/// Argon2id PHC string for `pin`, for `arming.pin_hash`.
+pub fn hash(pin: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(pin.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| DmsError::Config(format!("cannot hash PIN: {}", e)))
+}
+
+/// Checks that `hash` is a PHC string, e.g. when loading the config.
+pub fn validate(hash: &str) -> Result<()> {
+    PasswordHash::new(hash)
+        .map(|_| ())
+        .map_err(|e| DmsError::Config(format!("arming.pin_hash: {}", e)))
+}
+
+pub fn verify(hash: &str, pin: &Pin) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(pin.expose().as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// PIN from `DMS_PIN`, else prompted for on the terminal without echo.
+pub fn read(prompt: &str) -> Result<Pin> {
+    if let Ok(pin) = std::env::var("DMS_PIN") {
+        return Ok(Pin::new(pin));
+    }
+    Ok(Pin::new(rpassword::prompt_password(prompt)?))
+}
+
+/// A new PIN for `hash-pin`, typed twice unless it comes from `DMS_PIN`.
+pub fn read_new() -> Result<Pin> {
+    let pin = read("New PIN: ")?;
+    if pin.expose().is_empty() {
+        return Err(DmsError::Config("PIN must not be empty".into()));
+    }
+    if std::env::var_os("DMS_PIN").is_none() && read("Repeat PIN: ")?.expose() != pin.expose() {
+        return Err(DmsError::Config("PINs do not match".into()));
+    }
+    Ok(pin)
+}
+
+/// Wrong PINs in a row before every PIN is refused for `LOCKOUT`.
+pub const MAX_ATTEMPTS: u32 = 5;
+pub const LOCKOUT: Duration = Duration::from_secs(300);
+
+/// Counts consecutive wrong PINs. After `MAX_ATTEMPTS` of them, PINs are not
+/// even checked until `LOCKOUT` has passed.
+#[derive(Debug, Default)]
+pub struct Attempts {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    /// Fails with the seconds left while locked out.
+    pub fn check(&mut self, now: Instant) -> std::result::Result<(), u64> {
+        match self.locked_until {
+            Some(until) if now < until => Err((until - now).as_secs().max(1)),
+            Some(_) => {
+                *self = Self::default();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Records a wrong PIN. Returns whether that started a lockout.
+    pub fn failed(&mut self, now: Instant) -> bool {
+        self.failures += 1;
+        if self.failures < MAX_ATTEMPTS {
+            return false;
+        }
+        self.locked_until = Some(now + LOCKOUT);
+        true
+    }
+
+    pub fn succeeded(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_out_after_repeated_failures() {
+        let start = Instant::now();
+        let mut attempts = Attempts::default();
+        for _ in 1..MAX_ATTEMPTS {
+            assert_eq!(attempts.check(start), Ok(()));
+            assert!(!attempts.failed(start));
+        }
+        assert!(attempts.failed(start));
+        assert_eq!(attempts.check(start), Err(LOCKOUT.as_secs()));
+        assert!(attempts.check(start + LOCKOUT / 2).is_err());
+
+        // Afterwards the count starts over
+        assert_eq!(attempts.check(start + LOCKOUT), Ok(()));
+        assert!(!attempts.failed(start + LOCKOUT));
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let now = Instant::now();
+        let mut attempts = Attempts::default();
+        for _ in 1..MAX_ATTEMPTS {
+            attempts.failed(now);
+        }
+        attempts.succeeded();
+        assert!(!attempts.failed(now));
+        assert_eq!(attempts.check(now), Ok(()));
+    }
+}
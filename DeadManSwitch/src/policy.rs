@@ -0,0 +1,128 @@
+use crate::config::{ActionConfig, ActionKind, AfterProfile, Config};
+use crate::error::{DmsError, Result};
+use crate::triggers::TriggerSource;
+
+/// Used when no profile is configured for a source and there is no default.
+/// Its steps are the top-level `[[actions]]`, else broadcast, dismount and
+/// shut down. Can also be overridden by a profile with the same name.
+pub const BUILTIN_PROFILE: &str = "full";
+
+fn builtin_actions() -> Vec<ActionConfig> {
+    vec![
+        ActionConfig::new(ActionKind::Broadcast),
+        // Gives the alert window time to appear
+        ActionConfig { delay: 3, ..ActionConfig::new(ActionKind::DismountVeracrypt) },
+        ActionConfig::new(ActionKind::Shutdown),
+    ]
+}
+
+/// The profile selected for a trigger source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub actions: Vec<ActionConfig>,
+    pub after: AfterProfile,
+}
+
+/// `policy.sources.<source>`, else `policy.default`, else `full`.
+pub fn select(config: &Config, source: TriggerSource) -> Profile {
+    let name = config.policy.sources.get(&source.to_string())
+        .or(config.policy.default.as_ref())
+        .map(String::as_str)
+        .unwrap_or(BUILTIN_PROFILE);
+
+    let actions = actions(config, name).unwrap_or_default();
+    // A profile that shuts down leaves nothing to guard
+    let shuts_down = actions.iter().any(|a| a.kind == ActionKind::Shutdown);
+    let after = config.profiles.get(name).and_then(|p| p.after)
+        .unwrap_or(if shuts_down { AfterProfile::Exit } else { AfterProfile::Arm });
+    Profile { name: name.to_string(), actions, after }
+}
+
+/// Every action the default profile or a per-source profile can run.
+pub fn actions_in_use(config: &Config) -> Vec<ActionConfig> {
+    let mut names: Vec<&str> = config.policy.sources.values().map(String::as_str).collect();
+    names.push(config.policy.default.as_deref().unwrap_or(BUILTIN_PROFILE));
+
+    let mut used = Vec::new();
+    for action in names.into_iter().filter_map(|name| actions(config, name)).flatten() {
+        if !used.contains(&action) {
+            used.push(action);
+        }
+    }
+    used
+}
+
+/// Rejects references to undefined profiles, profiles without actions,
+/// invalid steps, and `[[actions]]` next to a `full` profile.
+pub fn validate(config: &Config) -> Result<()> {
+    if !config.actions.is_empty() && config.profiles.contains_key(BUILTIN_PROFILE) {
+        return Err(DmsError::Config(format!(
+            "actions and profiles.{} both define the {} profile", BUILTIN_PROFILE, BUILTIN_PROFILE)));
+    }
+    for (name, profile) in &config.profiles {
+        if profile.actions.is_empty() {
+            return Err(DmsError::Config(format!("profiles.{} has no actions", name)));
+        }
+    }
+
+    let pipelines = std::iter::once(("actions".to_string(), &config.actions))
+        .chain(config.profiles.iter().map(|(name, p)| (format!("profiles.{}.actions", name), &p.actions)));
+    for (key, steps) in pipelines {
+        for (i, step) in steps.iter().enumerate() {
+            validate_step(step).map_err(|e| DmsError::Config(format!("{}[{}]: {}", key, i, e)))?;
+        }
+    }
+
+    let referenced = config.policy.default.iter()
+        .map(|name| ("policy.default".to_string(), name))
+        .chain(config.policy.sources.iter()
+            .map(|(source, name)| (format!("policy.sources.{}", source), name)));
+    for (key, name) in referenced {
+        if actions(config, name).is_none() {
+            return Err(DmsError::Config(format!("{}: unknown profile '{}'", key, name)));
+        }
+    }
+    Ok(())
+}
+
+fn validate_step(step: &ActionConfig) -> std::result::Result<(), String> {
+    if step.timeout == Some(0) {
+        return Err("timeout must be at least 1 second".into());
+    }
+    match step.kind {
+        ActionKind::Exec if step.program.is_none() => return Err("exec needs a program".into()),
+        ActionKind::CloseLuks if !cfg!(target_os = "linux") => {
+            return Err("close_luks is only available on Linux".into());
+        }
+        _ if step.program.as_deref() == Some("") => return Err("program must not be empty".into()),
+        _ => {}
+    }
+
+    // Settings that only some actions take
+    use ActionKind::*;
+    let settings: [(&str, bool, &[ActionKind]); 6] = [
+        ("program", step.program.is_some(), &[Exec, DismountVeracrypt]),
+        ("args", !step.args.is_empty(), &[Exec]),
+        ("working_dir", step.working_dir.is_some(), &[Exec]),
+        ("mappings", !step.mappings.is_empty(), &[CloseLuks]),
+        ("volumes", !step.volumes.is_empty(), &[DismountVeracrypt]),
+        ("retries", step.retries.is_some(), &[DismountVeracrypt]),
+    ];
+    for (setting, set, kinds) in settings {
+        if set && !kinds.contains(&step.kind) {
+            let valid = kinds.iter().map(ActionKind::name).collect::<Vec<_>>().join(" and ");
+            return Err(format!("{} is only valid for {}, not {}", setting, valid, step.name()));
+        }
+    }
+    Ok(())
+}
+
+fn actions(config: &Config, name: &str) -> Option<Vec<ActionConfig>> {
+    match config.profiles.get(name) {
+        Some(profile) => Some(profile.actions.clone()),
+        None if name == BUILTIN_PROFILE && !config.actions.is_empty() => Some(config.actions.clone()),
+        None if name == BUILTIN_PROFILE => Some(builtin_actions()),
+        None => None,
+    }
+}
@@ -0,0 +1,302 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::VecDeque;
+use crate::config::{RuleConfig, RuleKind};
+use crate::error::{DmsError, Result};
+use crate::triggers::{TriggerEvent, TriggerSource};
+
+/// Events kept for the windows; older ones are dropped first.
+const MAX_HISTORY: usize = 1024;
+
+/// Sits between the trigger channel and the executor. Events from sources
+/// that no rule names pass straight through; the others are recorded and
+/// only fire once a rule is satisfied. `Manual` events always pass.
+#[derive(Debug, Default)]
+pub struct RuleEngine {
+    rules: Vec<RuleConfig>,
+    history: VecDeque<(String, DateTime<Utc>)>,
+}
+
+impl RuleEngine {
+    pub fn new(rules: &[RuleConfig]) -> Self {
+        Self { rules: rules.to_vec(), history: VecDeque::new() }
+    }
+
+    /// Forgets recorded events, e.g. when the switch stops being armed.
+    pub fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    /// The event to act on, if any: `event` itself when no rule covers its
+    /// source, or a `Rule` event once it completes a rule.
+    pub fn evaluate(&mut self, event: TriggerEvent) -> Option<TriggerEvent> {
+        let source = event.source.to_string();
+        let covered: Vec<&RuleConfig> = self.rules.iter()
+            .filter(|rule| rule.sources.contains(&source))
+            .collect();
+        if event.source == TriggerSource::Manual || covered.is_empty() {
+            return Some(event);
+        }
+
+        let horizon = self.rules.iter().map(|rule| rule.window).max().unwrap_or(0);
+        let cutoff = event.time - seconds(horizon);
+        while self.history.front().is_some_and(|(_, time)| *time < cutoff)
+            || self.history.len() >= MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((source.clone(), event.time));
+
+        for rule in covered {
+            if let Some(matched) = matches(rule, &self.history, event.time) {
+                log::warn!("[!] Rule '{}' satisfied by {}", rule.name, matched.join(", "));
+                let mut fired = TriggerEvent::new(TriggerSource::Rule)
+                    .with("rule", &rule.name)
+                    .with("matched", matched.join(","));
+                fired.time = event.time;
+                for (key, value) in &event.details {
+                    fired.details.insert(format!("{}_{}", source, key), value.clone());
+                }
+                return Some(fired);
+            }
+            log::warn!("[!] Trigger from {} recorded for rule '{}'", source, rule.name);
+        }
+        None
+    }
+}
+
+/// The matched sources, in event order, if `rule` holds for the events in
+/// its window ending at `now`. The last entry in `history` is the new event.
+fn matches(rule: &RuleConfig, history: &VecDeque<(String, DateTime<Utc>)>, now: DateTime<Utc>) -> Option<Vec<String>> {
+    let (current, _) = history.back()?;
+    let cutoff = now - seconds(rule.window);
+    let window: Vec<&String> = history.iter()
+        .filter(|(source, time)| *time >= cutoff && rule.sources.contains(source))
+        .map(|(source, _)| source)
+        .collect();
+
+    let mut seen: Vec<String> = Vec::new();
+    for source in &window {
+        if !seen.contains(source) {
+            seen.push(source.to_string());
+        }
+    }
+
+    let holds = match rule.kind {
+        RuleKind::Any => true,
+        RuleKind::All => rule.sources.iter().all(|s| seen.contains(s)),
+        RuleKind::AtLeast => seen.len() >= rule.count.unwrap_or(rule.sources.len()),
+        RuleKind::Sequence => {
+            // Earliest match for every step but the last, which must be the new event
+            let (last, steps) = rule.sources.split_last()?;
+            let mut next = 0;
+            for source in &window[..window.len() - 1] {
+                if next < steps.len() && **source == steps[next] {
+                    next += 1;
+                }
+            }
+            seen = rule.sources.clone();
+            next == steps.len() && current == last
+        }
+    };
+    holds.then_some(seen)
+}
+
+fn seconds(secs: u64) -> Duration {
+    Duration::seconds(secs.min(i64::MAX as u64 / 1000) as i64)
+}
+
+/// Rejects rules that could never fire or are ambiguous.
+pub fn validate(rules: &[RuleConfig]) -> Result<()> {
+    for (i, rule) in rules.iter().enumerate() {
+        let key = format!("rules[{}]", i);
+        if rule.name.is_empty() {
+            return Err(DmsError::Config(format!("{}: name is required", key)));
+        }
+        if rules[..i].iter().any(|r| r.name == rule.name) {
+            return Err(DmsError::Config(format!("{}: duplicate rule name '{}'", key, rule.name)));
+        }
+
+        let key = format!("rules.{}", rule.name);
+        if rule.sources.is_empty() {
+            return Err(DmsError::Config(format!("{}: sources is empty", key)));
+        }
+        for source in &rule.sources {
+            if source == "manual" || source == "rule" {
+                return Err(DmsError::Config(format!("{}: '{}' cannot be used in a rule", key, source)));
+            }
+        }
+        if rule.kind != RuleKind::Any && rule.window == 0 {
+            return Err(DmsError::Config(format!("{}: window must be greater than 0", key)));
+        }
+
+        match (rule.kind, rule.count) {
+            (RuleKind::AtLeast, Some(n)) if n >= 1 && n <= distinct(&rule.sources) => {}
+            (RuleKind::AtLeast, _) => return Err(DmsError::Config(format!(
+                "{}: count must be between 1 and the number of sources", key))),
+            (_, Some(_)) => return Err(DmsError::Config(format!(
+                "{}: count is only used by at_least rules", key))),
+            (RuleKind::Sequence, None) if rule.sources.len() < 2 => return Err(DmsError::Config(
+                format!("{}: a sequence needs at least two sources", key))),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn distinct(sources: &[String]) -> usize {
+    sources.iter().enumerate().filter(|(i, s)| !sources[..*i].contains(s)).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: RuleKind, sources: &[&str], count: Option<usize>, window: u64) -> RuleConfig {
+        RuleConfig {
+            name: "test".into(),
+            kind,
+            sources: sources.iter().map(|s| s.to_string()).collect(),
+            count,
+            window,
+        }
+    }
+
+    fn event(source: TriggerSource, at: i64) -> TriggerEvent {
+        let mut event = TriggerEvent::new(source);
+        event.time = DateTime::from_timestamp(1_700_000_000 + at, 0).unwrap();
+        event
+    }
+
+    fn fires(engine: &mut RuleEngine, source: TriggerSource, at: i64) -> bool {
+        engine.evaluate(event(source, at)).is_some()
+    }
+
+    #[test]
+    fn uncovered_sources_pass_through() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::All, &["usb", "timer"], None, 600)]);
+        let passed = engine.evaluate(event(TriggerSource::Network, 0)).unwrap();
+        assert_eq!(passed.source, TriggerSource::Network);
+        assert!(fires(&mut engine, TriggerSource::Manual, 0));
+    }
+
+    #[test]
+    fn no_rules_pass_everything() {
+        let mut engine = RuleEngine::new(&[]);
+        assert!(fires(&mut engine, TriggerSource::Usb, 0));
+    }
+
+    #[test]
+    fn any_fires_on_each_source() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::Any, &["usb", "flic"], None, 0)]);
+        let fired = engine.evaluate(event(TriggerSource::Flic, 0)).unwrap();
+        assert_eq!(fired.source, TriggerSource::Rule);
+        assert_eq!(fired.details["matched"], "flic");
+        assert!(fires(&mut engine, TriggerSource::Usb, 5000));
+    }
+
+    #[test]
+    fn all_needs_every_source_within_window() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::All, &["usb", "timer"], None, 600)]);
+        assert!(!fires(&mut engine, TriggerSource::Usb, 0));
+        assert!(!fires(&mut engine, TriggerSource::Usb, 100));
+
+        let fired = engine.evaluate(event(TriggerSource::Timer, 650)).unwrap();
+        assert_eq!(fired.details["rule"], "test");
+        assert_eq!(fired.details["matched"], "usb,timer");
+        assert_eq!(fired.time, event(TriggerSource::Timer, 650).time);
+    }
+
+    #[test]
+    fn all_ignores_events_outside_window() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::All, &["usb", "timer"], None, 600)]);
+        assert!(!fires(&mut engine, TriggerSource::Usb, 0));
+        assert!(!fires(&mut engine, TriggerSource::Timer, 601));
+        assert!(fires(&mut engine, TriggerSource::Usb, 900));
+    }
+
+    #[test]
+    fn at_least_counts_distinct_sources() {
+        let sources = ["usb", "network", "telegram"];
+        let mut engine = RuleEngine::new(&[rule(RuleKind::AtLeast, &sources, Some(2), 60)]);
+        assert!(!fires(&mut engine, TriggerSource::Usb, 0));
+        assert!(!fires(&mut engine, TriggerSource::Usb, 10));
+        assert!(fires(&mut engine, TriggerSource::Telegram, 20));
+    }
+
+    #[test]
+    fn at_least_window_slides() {
+        let sources = ["usb", "network", "telegram"];
+        let mut engine = RuleEngine::new(&[rule(RuleKind::AtLeast, &sources, Some(3), 60)]);
+        assert!(!fires(&mut engine, TriggerSource::Usb, 0));
+        assert!(!fires(&mut engine, TriggerSource::Network, 30));
+        assert!(!fires(&mut engine, TriggerSource::Telegram, 61));
+        assert!(fires(&mut engine, TriggerSource::Usb, 62));
+    }
+
+    #[test]
+    fn sequence_requires_order() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::Sequence, &["usb", "network"], None, 300)]);
+        assert!(!fires(&mut engine, TriggerSource::Network, 0));
+        assert!(!fires(&mut engine, TriggerSource::Usb, 10));
+
+        let fired = engine.evaluate(event(TriggerSource::Network, 20)).unwrap();
+        assert_eq!(fired.details["matched"], "usb,network");
+    }
+
+    #[test]
+    fn sequence_must_end_with_last_step() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::Sequence, &["usb", "network", "flic"], None, 300)]);
+        assert!(!fires(&mut engine, TriggerSource::Usb, 0));
+        assert!(!fires(&mut engine, TriggerSource::Flic, 10));
+        assert!(!fires(&mut engine, TriggerSource::Network, 20));
+        assert!(fires(&mut engine, TriggerSource::Flic, 30));
+    }
+
+    #[test]
+    fn sequence_within_window() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::Sequence, &["usb", "network"], None, 300)]);
+        assert!(!fires(&mut engine, TriggerSource::Usb, 0));
+        assert!(!fires(&mut engine, TriggerSource::Network, 301));
+    }
+
+    #[test]
+    fn rules_are_alternatives() {
+        let mut engine = RuleEngine::new(&[
+            rule(RuleKind::All, &["usb", "timer"], None, 600),
+            RuleConfig { name: "panic".into(), ..rule(RuleKind::Any, &["flic"], None, 0) },
+        ]);
+        assert!(!fires(&mut engine, TriggerSource::Usb, 0));
+        let fired = engine.evaluate(event(TriggerSource::Flic, 10)).unwrap();
+        assert_eq!(fired.details["rule"], "panic");
+    }
+
+    #[test]
+    fn clear_forgets_history() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::All, &["usb", "timer"], None, 600)]);
+        assert!(!fires(&mut engine, TriggerSource::Usb, 0));
+        engine.clear();
+        assert!(!fires(&mut engine, TriggerSource::Timer, 10));
+    }
+
+    #[test]
+    fn rule_event_keeps_trigger_details() {
+        let mut engine = RuleEngine::new(&[rule(RuleKind::Any, &["usb"], None, 0)]);
+        let fired = engine.evaluate(event(TriggerSource::Usb, 0).with("vendor_id", "090c")).unwrap();
+        assert_eq!(fired.details["usb_vendor_id"], "090c");
+    }
+
+    #[test]
+    fn validate_rejects_bad_rules() {
+        let ok = rule(RuleKind::AtLeast, &["usb", "timer"], Some(2), 600);
+        assert!(validate(std::slice::from_ref(&ok)).is_ok());
+
+        assert!(validate(&[RuleConfig { name: String::new(), ..ok.clone() }]).is_err());
+        assert!(validate(&[ok.clone(), ok.clone()]).is_err());
+        assert!(validate(&[RuleConfig { count: Some(3), ..ok.clone() }]).is_err());
+        assert!(validate(&[RuleConfig { count: None, ..ok.clone() }]).is_err());
+        assert!(validate(&[RuleConfig { window: 0, ..ok.clone() }]).is_err());
+        assert!(validate(&[rule(RuleKind::All, &[], None, 600)]).is_err());
+        assert!(validate(&[rule(RuleKind::All, &["usb"], Some(1), 600)]).is_err());
+        assert!(validate(&[rule(RuleKind::Any, &["manual"], None, 0)]).is_err());
+        assert!(validate(&[rule(RuleKind::Sequence, &["usb"], None, 600)]).is_err());
+    }
+}
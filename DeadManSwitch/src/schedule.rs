@@ -0,0 +1,215 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::error::{DmsError, Result};
+
+/// When a trigger is armed, in local time: the union of its entries.
+///
+/// Entries are `<days> [HH:MM-HH:MM]`, `HH:MM-HH:MM` (every day) or
+/// `YYYY-MM-DD..YYYY-MM-DD` (whole days, inclusive). Days are `mon`..`sun`,
+/// ranges like `mon-fri`, comma-separated lists, or `*`. A time range that
+/// ends before it starts runs past midnight into the next day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Entry {
+    Weekly { days: [bool; 7], from: NaiveTime, to: Option<NaiveTime> },
+    Dates { from: NaiveDate, to: NaiveDate },
+}
+
+impl Schedule {
+    pub fn parse(entries: &[String]) -> std::result::Result<Self, String> {
+        if entries.is_empty() {
+            return Err("schedule is empty".into());
+        }
+        let entries = entries.iter()
+            .map(|e| parse_entry(e).map_err(|msg| format!("'{}': {}", e, msg)))
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Self { entries })
+    }
+
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        self.entries.iter().any(|entry| entry.contains(now))
+    }
+}
+
+impl Entry {
+    fn contains(&self, now: NaiveDateTime) -> bool {
+        let (date, time) = (now.date(), now.time());
+        match *self {
+            Entry::Dates { from, to } => from <= date && date <= to,
+            Entry::Weekly { days, from, to: None } => days[weekday(date)] && time >= from,
+            Entry::Weekly { days, from, to: Some(to) } if from < to => {
+                days[weekday(date)] && from <= time && time < to
+            }
+            // Past midnight: the evening part today, or the morning part of
+            // a range that started yesterday
+            Entry::Weekly { days, from, to: Some(to) } => {
+                (days[weekday(date)] && time >= from)
+                    || (days[weekday(date - Duration::days(1))] && time < to)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries: Vec<String> = self.entries.iter().map(|entry| match entry {
+            Entry::Dates { from, to } => format!("{}..{}", from, to),
+            Entry::Weekly { days, from, to } => {
+                let days: Vec<&str> = match days {
+                    [true, true, true, true, true, true, true] => vec!["*"],
+                    _ => DAY_NAMES.iter().zip(days).filter(|(_, on)| **on).map(|(d, _)| *d).collect(),
+                };
+                match to {
+                    Some(to) => format!("{} {}-{}", days.join(","), from.format("%H:%M"), to.format("%H:%M")),
+                    None if *from == NaiveTime::MIN => days.join(","),
+                    None => format!("{} {}-24:00", days.join(","), from.format("%H:%M")),
+                }
+            }
+        }).collect();
+        f.write_str(&entries.join("; "))
+    }
+}
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+fn weekday(date: NaiveDate) -> usize {
+    date.weekday().num_days_from_monday() as usize
+}
+
+fn parse_entry(entry: &str) -> std::result::Result<Entry, String> {
+    let entry = entry.trim().to_lowercase();
+
+    if let Some((from, to)) = entry.split_once("..") {
+        let date = |s: &str| NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d")
+            .map_err(|_| format!("invalid date '{}' (expected YYYY-MM-DD)", s.trim()));
+        let (from, to) = (date(from)?, date(to)?);
+        if to < from {
+            return Err("date range ends before it starts".into());
+        }
+        return Ok(Entry::Dates { from, to });
+    }
+
+    let mut parts = entry.split_whitespace();
+    let (days, times) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(t), None, None) if t.contains(':') => ("*", Some(t)),
+        (Some(d), t, None) => (d, t),
+        _ => return Err("expected '<days> [HH:MM-HH:MM]'".into()),
+    };
+
+    let days = parse_days(days)?;
+    let (from, to) = match times {
+        None => (NaiveTime::MIN, None),
+        Some(range) => {
+            let (from, to) = range.split_once('-').ok_or("expected HH:MM-HH:MM")?;
+            let from = parse_time(from)?.ok_or("a range cannot start at 24:00")?;
+            let to = parse_time(to)?;
+            if to == Some(from) {
+                return Err("time range is empty".into());
+            }
+            (from, to)
+        }
+    };
+    Ok(Entry::Weekly { days, from, to })
+}
+
+/// `None` for `24:00`, the end of the day.
+fn parse_time(value: &str) -> std::result::Result<Option<NaiveTime>, String> {
+    if value == "24:00" {
+        return Ok(None);
+    }
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .map(Some)
+        .map_err(|_| format!("invalid time '{}' (expected HH:MM)", value))
+}
+
+fn parse_days(value: &str) -> std::result::Result<[bool; 7], String> {
+    let mut days = [false; 7];
+    if value == "*" || value == "daily" {
+        return Ok([true; 7]);
+    }
+
+    for part in value.split(',') {
+        let day = |s: &str| s.parse::<Weekday>()
+            .map(|d| d.num_days_from_monday() as usize)
+            .map_err(|_| format!("invalid day '{}'", s));
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (day(from)?, day(to)?);
+                // Wraps around the week, e.g. fri-mon
+                let mut d = from;
+                loop {
+                    days[d] = true;
+                    if d == to {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+            None => days[day(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+/// Parses every `[schedules]` entry; triggers without one are always armed.
+pub fn parse_all(schedules: &BTreeMap<String, Vec<String>>) -> Result<BTreeMap<String, Schedule>> {
+    schedules.iter()
+        .map(|(name, entries)| {
+            Schedule::parse(entries)
+                .map(|schedule| (name.clone(), schedule))
+                .map_err(|e| DmsError::Config(format!("schedules.{}: {}", name, e)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(entries: &[&str]) -> Schedule {
+        Schedule::parse(&entries.iter().map(|e| e.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    // 2026-10-12 is a Monday
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, day).unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn office_hours_outside() {
+        let s = schedule(&["mon-fri 18:00-08:00", "sat,sun"]);
+        assert!(!s.is_active(at(12, "12:00")));
+        assert!(s.is_active(at(12, "18:00")));
+        assert!(s.is_active(at(13, "07:59")));
+        assert!(!s.is_active(at(13, "08:00")));
+        assert!(s.is_active(at(17, "10:00")));
+        // Sunday night is not covered by the weekday range
+        assert!(!s.is_active(at(12, "07:00")));
+    }
+
+    #[test]
+    fn ranges_and_dates() {
+        let s = schedule(&["fri-mon 09:00-24:00", "2026-10-14..2026-10-15"]);
+        assert!(s.is_active(at(12, "23:59")));
+        assert!(!s.is_active(at(13, "12:00")));
+        assert!(s.is_active(at(14, "00:00")));
+        assert!(s.is_active(at(15, "23:59")));
+        assert!(!s.is_active(at(16, "08:00")));
+        assert!(schedule(&["22:00-06:00"]).is_active(at(16, "05:00")));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        for entry in ["", "funday", "mon 9-17", "mon 10:00-10:00", "mon 24:00-10:00",
+                      "2026-10-15..2026-10-14", "mon 10:00-12:00 extra"] {
+            assert!(Schedule::parse(&[entry.to_string()]).is_err(), "{}", entry);
+        }
+        assert!(Schedule::parse(&[]).is_err());
+    }
+}
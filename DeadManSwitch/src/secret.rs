@@ -0,0 +1,185 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use zeroize::{Zeroize, Zeroizing};
+use crate::error::{DmsError, Result};
+
+/// Environment variables that hold secrets.
+const SECRET_VARS: &[&str] = &["DMS_TELEGRAM_BOT_TOKEN", "DMS_PIN"];
+
+/// Variables named by `env:` references, as they are resolved.
+static REFERENCED_VARS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Keeps the secrets in our environment out of `command`'s. Every program
+/// the switch runs goes through here.
+pub fn scrub_env(command: &mut Command) -> &mut Command {
+    let referenced = REFERENCED_VARS.lock().unwrap_or_else(|e| e.into_inner());
+    for name in SECRET_VARS.iter().copied().chain(referenced.iter().map(String::as_str)) {
+        command.env_remove(name);
+    }
+    command
+}
+
+/// A secret value, e.g. the Telegram bot token.
+///
+/// The value is redacted from `Debug`, `Display` and `Serialize`, and wiped
+/// from memory when the last clone is dropped (clones share one allocation).
+#[derive(Clone)]
+pub struct Secret(Arc<Zeroizing<String>>);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(Arc::new(Zeroizing::new(value)))
+    }
+
+    pub fn expose(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Resolves a secret reference:
+    ///
+    /// - `file:<path>` – contents of the file, surrounding whitespace trimmed
+    /// - `env:<NAME>` – value of an environment variable
+    /// - `keyring:<description>` – a `user` key in the Linux kernel user keyring
+    /// - anything else is taken literally
+    pub fn resolve(reference: String) -> Result<Self> {
+        let reference = Zeroizing::new(reference);
+
+        if let Some(path) = reference.strip_prefix("file:") {
+            let content = Zeroizing::new(std::fs::read_to_string(path)
+                .map_err(|e| DmsError::Config(format!("secret file {}: {}", path, e)))?);
+            Self::non_empty(content.trim().to_string(), &reference)
+        } else if let Some(name) = reference.strip_prefix("env:") {
+            REFERENCED_VARS.lock().unwrap_or_else(|e| e.into_inner()).insert(name.to_string());
+            let value = std::env::var(name)
+                .map_err(|e| DmsError::Config(format!("secret env {}: {}", name, e)))?;
+            Self::non_empty(value, &reference)
+        } else if let Some(description) = reference.strip_prefix("keyring:") {
+            Self::non_empty(read_keyring(description)?, &reference)
+        } else {
+            Self::non_empty(reference.to_string(), "literal")
+        }
+    }
+
+    fn non_empty(value: String, origin: &str) -> Result<Self> {
+        if value.is_empty() {
+            return Err(DmsError::Config(format!("secret from {} is empty", origin)));
+        }
+        Ok(Self::new(value))
+    }
+
+}
+
+/// A secret reference as given on the command line or in the environment,
+/// kept unresolved so that every config load (including a reload) reads the
+/// file or keyring again. Redacted like `Secret`.
+#[derive(Clone, PartialEq)]
+pub struct SecretRef(Secret);
+
+impl SecretRef {
+    /// Adapter for clap's `value_parser`.
+    pub fn parse(reference: &str) -> std::result::Result<Self, String> {
+        Ok(Self(Secret::new(reference.to_string())))
+    }
+
+    pub fn resolve(&self) -> Result<Secret> {
+        Secret::resolve(self.0.expose().to_string())
+    }
+}
+
+impl fmt::Debug for SecretRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretRef(<redacted>)")
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_keyring(description: &str) -> Result<String> {
+    let search = scrub_env(&mut Command::new("keyctl"))
+        .args(["search", "@u", "user", description])
+        .output()
+        .map_err(|e| DmsError::Config(format!("keyctl: {}", e)))?;
+    if !search.status.success() {
+        return Err(DmsError::Config(format!("keyring: no user key '{}'", description)));
+    }
+    let id = String::from_utf8_lossy(&search.stdout).trim().to_string();
+
+    let mut pipe = scrub_env(&mut Command::new("keyctl"))
+        .args(["pipe", &id])
+        .output()
+        .map_err(|e| DmsError::Config(format!("keyctl: {}", e)))?;
+    pipe.stderr.zeroize();
+    if !pipe.status.success() {
+        pipe.stdout.zeroize();
+        return Err(DmsError::Config(format!("keyring: cannot read key '{}'", description)));
+    }
+
+    let value = String::from_utf8(pipe.stdout).map_err(|e| {
+        e.into_bytes().zeroize();
+        DmsError::Config(format!("keyring: key '{}' is not UTF-8", description))
+    })?;
+    let value = Zeroizing::new(value);
+    Ok(value.trim().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_keyring(_description: &str) -> Result<String> {
+    Err(DmsError::Config("keyring: secrets are only supported on Linux".into()))
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.expose() == other.expose()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let reference = String::deserialize(deserializer)?;
+        Self::resolve(reference).map_err(|e| match e {
+            DmsError::Config(msg) => serde::de::Error::custom(msg),
+            other => serde::de::Error::custom(other),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrubs_secret_vars_from_children() {
+        // The variable need not be set: naming it marks it as a secret
+        assert!(Secret::resolve("env:DMS_TEST_SECRET_VAR".to_string()).is_err());
+
+        let mut command = Command::new("true");
+        scrub_env(&mut command);
+        let removed: Vec<_> = command.get_envs()
+            .filter(|(_, value)| value.is_none())
+            .map(|(name, _)| name.to_string_lossy().into_owned())
+            .collect();
+        for name in ["DMS_TELEGRAM_BOT_TOKEN", "DMS_PIN", "DMS_TEST_SECRET_VAR"] {
+            assert!(removed.iter().any(|r| r == name), "{} not removed: {:?}", name, removed);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+use std::fmt;
+use std::time::Duration;
+use crate::audit;
+
+/// Whether trigger events are acted on. Only `Armed` fires the actions (or
+/// any state, for a manual fire); `Triggered` lasts until the profile has run, see `resume`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwitchState {
+    /// Grace period before `Armed`, after startup or a re-arm
+    Arming { until: DateTime<Utc> },
+    Armed,
+    Disarmed,
+    Paused { until: DateTime<Utc> },
+    Triggered,
+}
+
+impl SwitchState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SwitchState::Arming { .. } => "arming",
+            SwitchState::Armed => "armed",
+            SwitchState::Disarmed => "disarmed",
+            SwitchState::Paused { .. } => "paused",
+            SwitchState::Triggered => "triggered",
+        }
+    }
+}
+
+impl fmt::Display for SwitchState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwitchState::Arming { until } | SwitchState::Paused { until } => {
+                write!(f, "{} until {}", self.name(), until.to_rfc3339_opts(SecondsFormat::Secs, true))
+            }
+            other => f.write_str(other.name()),
+        }
+    }
+}
+
+/// The switch state plus the allowed transitions. Every transition is logged
+/// with its reason.
+#[derive(Debug)]
+pub struct StateMachine {
+    state: SwitchState,
+    grace: Duration,
+}
+
+impl StateMachine {
+    /// Starts in the grace period, or armed if `grace` is zero.
+    pub fn new(grace: Duration) -> Self {
+        let mut machine = Self { state: SwitchState::Disarmed, grace };
+        machine.start_arming("startup");
+        machine
+    }
+
+    pub fn state(&self) -> &SwitchState {
+        &self.state
+    }
+
+    /// Applies to the next re-arm, e.g. after a config reload.
+    pub fn set_grace(&mut self, grace: Duration) {
+        self.grace = grace;
+    }
+
+    /// Re-armed (after the grace period) from `Disarmed` or `Paused`.
+    pub fn arm(&mut self, reason: &str) -> Result<(), String> {
+        match self.state {
+            SwitchState::Disarmed | SwitchState::Paused { .. } => {
+                self.start_arming(reason);
+                Ok(())
+            }
+            ref other => Err(format!("switch is {}", other)),
+        }
+    }
+
+    pub fn disarm(&mut self, reason: &str) -> Result<(), String> {
+        match self.state {
+            SwitchState::Arming { .. } | SwitchState::Armed | SwitchState::Paused { .. } => {
+                self.transition(SwitchState::Disarmed, reason);
+                Ok(())
+            }
+            ref other => Err(format!("switch is {}", other)),
+        }
+    }
+
+    /// Stands down until `until`, then `advance` re-arms. Only an armed (or
+    /// arming) switch can be paused, or a paused one given a new deadline,
+    /// so that expiry never arms a switch that was disarmed.
+    pub fn pause(&mut self, until: DateTime<Utc>, reason: &str) -> Result<(), String> {
+        if until <= Utc::now() {
+            return Err("pause deadline is in the past".into());
+        }
+        match self.state {
+            SwitchState::Arming { .. } | SwitchState::Armed | SwitchState::Paused { .. } => {
+                self.transition(SwitchState::Paused { until }, reason);
+                Ok(())
+            }
+            ref other => Err(format!("switch is {}", other)),
+        }
+    }
+
+    /// Moves to `Triggered` if armed. Returns whether the event should fire.
+    pub fn trigger(&mut self, reason: &str) -> bool {
+        if self.state != SwitchState::Armed {
+            return false;
+        }
+        self.transition(SwitchState::Triggered, reason);
+        true
+    }
+
+    /// Moves to `Triggered` from any other state: a manual fire is not held
+    /// back by the grace period, a pause or a disarm. Returns whether it did.
+    pub fn fire(&mut self, reason: &str) -> bool {
+        if self.state == SwitchState::Triggered {
+            return false;
+        }
+        self.transition(SwitchState::Triggered, reason);
+        true
+    }
+
+    /// Leaves `Triggered` once a profile that does not exit has run: re-armed
+    /// (after the grace period) if `rearm`, else disarmed.
+    pub fn resume(&mut self, rearm: bool, reason: &str) -> Result<(), String> {
+        match self.state {
+            SwitchState::Triggered if rearm => self.start_arming(reason),
+            SwitchState::Triggered => self.transition(SwitchState::Disarmed, reason),
+            ref other => return Err(format!("switch is {}", other)),
+        }
+        Ok(())
+    }
+
+    /// When the current pause or grace period ends.
+    pub fn deadline(&self) -> Option<DateTime<Utc>> {
+        match self.state {
+            SwitchState::Arming { until } | SwitchState::Paused { until } => Some(until),
+            _ => None,
+        }
+    }
+
+    /// Leaves an expired pause (into the grace period) or grace period (into
+    /// `Armed`). Returns the new state if it changed.
+    pub fn advance(&mut self) -> Option<&SwitchState> {
+        if self.deadline()? > Utc::now() {
+            return None;
+        }
+        match self.state {
+            SwitchState::Paused { .. } => self.start_arming("pause expired"),
+            _ => self.transition(SwitchState::Armed, "grace period over"),
+        }
+        Some(&self.state)
+    }
+
+    fn start_arming(&mut self, reason: &str) {
+        if self.grace.is_zero() {
+            self.transition(SwitchState::Armed, reason);
+        } else {
+            let until = Utc::now() + chrono::Duration::from_std(self.grace).unwrap_or_default();
+            self.transition(SwitchState::Arming { until }, reason);
+        }
+    }
+
+    fn transition(&mut self, to: SwitchState, reason: &str) {
+        log::warn!(state:% = to; "[!] State: {} -> {} ({})", self.state, to, reason);
+        audit::record_sync("state", json!({
+            "from": self.state.to_string(),
+            "to": to.to_string(),
+            "reason": reason,
+        }));
+        self.state = to;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn in_secs(secs: i64) -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::seconds(secs)
+    }
+
+    fn machine(state: SwitchState, grace: u64) -> StateMachine {
+        StateMachine { state, grace: Duration::from_secs(grace) }
+    }
+
+    #[test]
+    fn starts_arming_or_armed() {
+        assert_eq!(StateMachine::new(Duration::ZERO).state(), &SwitchState::Armed);
+        let mut arming = StateMachine::new(Duration::from_secs(30));
+        assert_eq!(arming.state().name(), "arming");
+        assert!(arming.advance().is_none());
+        assert!(!arming.trigger("too early"));
+    }
+
+    #[test]
+    fn arm_and_disarm() {
+        let mut m = machine(SwitchState::Armed, 0);
+        assert!(m.arm("again").is_err());
+        m.disarm("test").unwrap();
+        assert_eq!(m.state(), &SwitchState::Disarmed);
+        assert!(m.disarm("again").is_err());
+        assert!(!m.trigger("while disarmed"));
+        m.arm("test").unwrap();
+        assert_eq!(m.state(), &SwitchState::Armed);
+
+        let mut m = machine(SwitchState::Disarmed, 30);
+        m.arm("test").unwrap();
+        assert_eq!(m.state().name(), "arming");
+        m.disarm("during grace").unwrap();
+        assert_eq!(m.state(), &SwitchState::Disarmed);
+    }
+
+    #[test]
+    fn pause_only_when_armed() {
+        let mut m = machine(SwitchState::Disarmed, 0);
+        assert_eq!(m.pause(in_secs(600), "test"), Err("switch is disarmed".into()));
+
+        let mut m = machine(SwitchState::Armed, 0);
+        assert!(m.pause(in_secs(-1), "test").is_err());
+        m.pause(in_secs(600), "test").unwrap();
+        assert_eq!(m.state().name(), "paused");
+        assert!(!m.trigger("while paused"));
+        m.pause(in_secs(60), "shorter").unwrap();
+        assert!(m.deadline().unwrap() < in_secs(61));
+
+        m.disarm("test").unwrap();
+        assert_eq!(m.state(), &SwitchState::Disarmed);
+    }
+
+    #[test]
+    fn expiry_rearms() {
+        let mut m = machine(SwitchState::Paused { until: in_secs(-1) }, 30);
+        assert_eq!(m.advance().map(SwitchState::name), Some("arming"));
+        assert!(m.advance().is_none());
+
+        let mut m = machine(SwitchState::Paused { until: in_secs(-1) }, 0);
+        assert_eq!(m.advance(), Some(&SwitchState::Armed));
+
+        let mut m = machine(SwitchState::Arming { until: in_secs(-1) }, 30);
+        assert_eq!(m.advance(), Some(&SwitchState::Armed));
+        assert!(m.advance().is_none());
+    }
+
+    #[test]
+    fn manual_fire_in_any_state_but_triggered() {
+        let states = [
+            SwitchState::Arming { until: in_secs(30) },
+            SwitchState::Armed,
+            SwitchState::Disarmed,
+            SwitchState::Paused { until: in_secs(600) },
+        ];
+        for state in states {
+            let mut m = machine(state, 30);
+            assert!(m.fire("manual"));
+            assert_eq!(m.state(), &SwitchState::Triggered);
+            assert!(!m.fire("again"));
+        }
+    }
+
+    #[test]
+    fn triggered_until_resumed() {
+        let mut m = machine(SwitchState::Armed, 0);
+        assert!(m.resume(true, "not triggered").is_err());
+        assert!(m.trigger("test"));
+        assert!(!m.trigger("again"));
+        assert!(m.arm("test").is_err());
+        assert!(m.disarm("test").is_err());
+        assert!(m.pause(in_secs(600), "test").is_err());
+        assert!(m.advance().is_none());
+        assert_eq!(m.state(), &SwitchState::Triggered);
+
+        m.resume(true, "profile done").unwrap();
+        assert_eq!(m.state(), &SwitchState::Armed);
+        assert!(m.trigger("again"));
+        m.resume(false, "profile done").unwrap();
+        assert_eq!(m.state(), &SwitchState::Disarmed);
+
+        let mut m = machine(SwitchState::Triggered, 30);
+        m.resume(true, "profile done").unwrap();
+        assert_eq!(m.state().name(), "arming");
+    }
+}
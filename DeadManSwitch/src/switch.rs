@@ -0,0 +1,666 @@
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::actions::{ActionExecutor, ProgressSender};
+use crate::audit;
+use crate::config::{parse_trigger_name, AfterProfile, Config, ConfigSource};
+use crate::control::{ControlServer, Peer, Request, Response, Status, TriggerStatus};
+use crate::error::Result;
+use crate::logging;
+use crate::pin::{self, Pin};
+use crate::policy;
+use crate::rules::RuleEngine;
+use crate::schedule::{self, Schedule};
+use crate::state::{StateMachine, SwitchState};
+use crate::triggers::{
+    create_trigger_channel, hostname, Health, RunningTrigger, TriggerEvent, TriggerRegistry,
+    TriggerSender, TriggerSource,
+};
+use crate::triggers::telegram;
+
+/// Resolves once per reload request (SIGHUP). Never resolves on platforms
+/// without SIGHUP.
+pub struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl ReloadSignal {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.hangup.recv().await;
+
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Resolves on SIGTERM or Ctrl-C.
+pub struct StopSignal {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl StopSignal {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+        })
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Builds and spawns a supervised task for every enabled trigger in `names`,
+/// then waits (up to `supervisor.startup_timeout`) for each to report ready.
+async fn start_triggers(
+    registry: &TriggerRegistry,
+    names: &[&'static str],
+    config: &Config,
+    tx: &TriggerSender,
+) -> Vec<RunningTrigger> {
+    let mut spawned: Vec<RunningTrigger> = names.iter()
+        .filter_map(|name| registry.build(name, config))
+        .map(|trigger| RunningTrigger::spawn(trigger, tx.clone(), config.supervisor.clone()))
+        .collect();
+
+    let deadline = tokio::time::Instant::now()
+        + tokio::time::Duration::from_secs(config.supervisor.startup_timeout);
+    for trigger in &mut spawned {
+        match trigger.wait_ready(deadline).await {
+            Ok(()) => audit::record("trigger_up", json!({ "trigger": trigger.name })),
+            Err(e) => {
+                log::error!(trigger = trigger.name; "[!] {} trigger did not come up: {}", trigger.name, e);
+                audit::record("trigger_down", json!({ "trigger": trigger.name, "error": e.to_string() }));
+            }
+        }
+    }
+    spawned
+}
+
+/// The armed triggers and the config they were built from.
+struct Switch {
+    registry: TriggerRegistry,
+    names: Vec<&'static str>,
+    source: ConfigSource,
+    config: Config,
+    executor: ActionExecutor,
+    running: HashMap<&'static str, RunningTrigger>,
+    /// Disarmed over the control socket; kept stopped across reloads
+    disarmed: HashSet<&'static str>,
+    schedules: BTreeMap<String, Schedule>,
+    /// Stopped because they are outside their schedule
+    off_schedule: HashSet<&'static str>,
+    /// Whether each trigger was inside its schedule at the last check
+    scheduled: HashMap<&'static str, bool>,
+    state: StateMachine,
+    /// Wrong PINs over the control socket
+    pin_attempts: pin::Attempts,
+    rules: RuleEngine,
+    /// Last arming countdown value shown
+    countdown: Option<u64>,
+    tx: TriggerSender,
+}
+
+const COUNTDOWN_TICK: std::time::Duration = std::time::Duration::from_millis(250);
+
+const SCHEDULE_TICK: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How long the activation alert may hold up the actions.
+const ALERT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Seconds left at which the arming countdown notification is refreshed.
+const COUNTDOWN_STEPS: [u64; 8] = [60, 30, 20, 10, 5, 3, 2, 1];
+
+impl Switch {
+    /// Names of the triggers that are currently up, in registry order.
+    fn armed_triggers(&self) -> Vec<String> {
+        self.names.iter()
+            .filter_map(|name| self.running.get(name))
+            .filter(|t| t.health() == Health::Up)
+            .map(|t| t.name.to_string())
+            .collect()
+    }
+
+    /// Configured triggers that are not up, e.g. "failed: usb; restarting: flic",
+    /// or empty when all of them are.
+    fn trigger_problems(&self) -> String {
+        let pick = |wanted: fn(&Health) -> bool| -> Vec<&str> {
+            self.names.iter()
+                .filter_map(|name| self.running.get(name))
+                .filter(|t| wanted(&t.health()))
+                .map(|t| t.name)
+                .collect()
+        };
+        [
+            ("failed", pick(|h| matches!(h, Health::Failed(_)))),
+            ("restarting", pick(|h| matches!(h, Health::Starting | Health::Restarting))),
+        ]
+            .into_iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(label, names)| format!("{}: {}", label, names.join(", ")))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// Notification for the current state: the countdown while arming, or
+    /// "armed". `changed` forces one, e.g. right after a transition; otherwise
+    /// the countdown is only refreshed at `COUNTDOWN_STEPS`.
+    fn announce(&mut self, changed: bool) {
+        if changed {
+            self.countdown = None;
+        }
+        let armed = self.armed_triggers();
+        if armed.is_empty() {
+            self.countdown = None;
+            if changed && self.off_schedule.is_empty() {
+                log::error!("[!] No trigger came up, DMS is NOT armed");
+                ActionExecutor::notify("DMS NOT armed - no trigger came up");
+            } else if changed {
+                log::warn!("[!] No trigger is inside its schedule");
+            }
+            return;
+        }
+
+        let problems = self.trigger_problems();
+        match self.state.state() {
+            SwitchState::Arming { until } => {
+                let millis = (*until - Utc::now()).num_milliseconds().max(0) as u64;
+                let secs = millis.div_ceil(1000);
+                if self.countdown.is_none() {
+                    log::info!("[+] DMS arming in {}s: {}", secs, armed.join(", "));
+                } else if !COUNTDOWN_STEPS.contains(&secs) || self.countdown == Some(secs) {
+                    return;
+                }
+                self.countdown = Some(secs);
+                ActionExecutor::send_notification(&armed, &problems, Some(secs));
+            }
+            SwitchState::Armed if changed => {
+                log::info!("[+] DMS armed: {}", armed.join(", "));
+                if !problems.is_empty() {
+                    log::warn!("[!] Not armed: {}", problems);
+                }
+                ActionExecutor::send_notification(&armed, &problems, None);
+            }
+            _ => self.countdown = None,
+        }
+    }
+
+    fn status(&self) -> Status {
+        let mut heartbeat_remaining = None;
+        let mut triggers = Vec::new();
+
+        for name in &self.names {
+            let (state, remaining) = match self.running.get(name) {
+                Some(t) => (t.health().to_string(), t.remaining().map(|d| d.as_secs())),
+                None if self.disarmed.contains(name) => ("disarmed".to_string(), None),
+                None if self.off_schedule.contains(name) => ("off-schedule".to_string(), None),
+                None => continue,
+            };
+            if *name == "timer" {
+                heartbeat_remaining = remaining;
+            }
+            triggers.push(TriggerStatus { name: name.to_string(), state, remaining });
+        }
+
+        Status {
+            hostname: hostname(),
+            state: self.state.state().name().to_string(),
+            until: self.state.deadline()
+                .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            triggers,
+            heartbeat_remaining,
+        }
+    }
+
+    /// Re-reads the config; only triggers whose settings changed are restarted.
+    async fn reload(&mut self) -> Result<()> {
+        let result = self.apply_reload().await;
+        audit::record("reload", json!({
+            "ok": result.is_ok(),
+            "error": result.as_ref().err().map(|e| e.to_string()),
+        }));
+        result
+    }
+
+    async fn apply_reload(&mut self) -> Result<()> {
+        let new_config = self.source.load()?;
+        self.registry.validate(&new_config)?;
+
+        // Triggers whose settings are unchanged stay armed throughout
+        let changed: Vec<&'static str> = self.names.iter()
+            .copied()
+            .filter(|name| self.registry.changed(name, &self.config, &new_config))
+            .collect();
+
+        for name in &changed {
+            if let Some(trigger) = self.running.remove(name) {
+                log::warn!(trigger = name; "[!] Stopping {} trigger", name);
+                trigger.stop().await;
+            }
+        }
+
+        let restart: Vec<&'static str> = changed.into_iter()
+            .filter(|name| !self.disarmed.contains(name) && !self.off_schedule.contains(name))
+            .collect();
+        for trigger in start_triggers(&self.registry, &restart, &new_config, &self.tx).await {
+            if trigger.health() == Health::Up {
+                log::warn!(trigger = trigger.name; "[!] Started {} trigger", trigger.name);
+            }
+            self.running.insert(trigger.name, trigger);
+        }
+
+        self.executor = ActionExecutor::new(new_config.clone());
+        self.state.set_grace(grace_period(&new_config));
+        if new_config.rules != self.config.rules {
+            log::warn!("[!] Rules changed, recorded trigger events dropped");
+            self.rules = RuleEngine::new(&new_config.rules);
+        }
+        if new_config.logging != self.config.logging {
+            logging::configure(&new_config.logging);
+        }
+        self.schedules = schedule::parse_all(&new_config.schedules)?;
+        self.config = new_config;
+
+        // Re-evaluate every schedule against the new config
+        self.scheduled.clear();
+        self.apply_schedules().await;
+        Ok(())
+    }
+
+    /// Stops triggers that left their schedule and restarts those that
+    /// entered it. Only acts on changes, so `ctl arm`/`disarm` of a single
+    /// trigger holds until its next schedule transition.
+    async fn apply_schedules(&mut self) {
+        let now = Local::now().naive_local();
+        for name in self.names.clone() {
+            let active = self.schedules.get(name).is_none_or(|s| s.is_active(now));
+            if self.scheduled.insert(name, active) == Some(active) {
+                continue;
+            }
+
+            if active {
+                if !self.off_schedule.remove(name) {
+                    continue;
+                }
+                let Some(trigger) = start_triggers(&self.registry, &[name], &self.config, &self.tx).await.pop() else {
+                    continue;
+                };
+                let health = trigger.health();
+                self.running.insert(name, trigger);
+                if health == Health::Up {
+                    self.announce_change(&format!("{} trigger armed by schedule", name));
+                } else {
+                    self.announce_change(&format!("{} trigger did not come up ({})", name, health));
+                }
+            } else if let Some(trigger) = self.running.remove(name) {
+                trigger.stop().await;
+                self.off_schedule.insert(name);
+                self.announce_change(&format!("{} trigger disarmed by schedule", name));
+            }
+        }
+    }
+
+    /// Logs `msg` and sends it as a desktop notification and to the Telegram
+    /// status chat.
+    fn announce_change(&self, msg: &str) {
+        log::warn!("[!] {}", msg);
+        audit::record("announce", json!({ "message": msg }));
+        ActionExecutor::notify(&format!("DMS: {}", msg));
+
+        let config = self.config.clone();
+        let text = format!("🛡️ DMS on {}: {}", hostname(), msg);
+        tokio::spawn(async move {
+            if let Err(e) = telegram::announce(&config, &text).await {
+                log::error!("[!] Telegram announcement failed: {}", e);
+            }
+        });
+    }
+
+    /// Tells the Telegram chat an accepted event came from which profile
+    /// runs, before it does: a shutdown would cut the message off.
+    async fn alert(&self, event: &TriggerEvent, profile: &policy::Profile) {
+        let Some((chat_id, text)) = telegram::activation_alert(&self.config, event, profile) else {
+            return;
+        };
+        match tokio::time::timeout(ALERT_TIMEOUT, telegram::send(&self.config, chat_id, &text)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("[!] Failed to send Telegram alert: {}", e),
+            Err(_) => log::error!("[!] Telegram alert timed out after {}s", ALERT_TIMEOUT.as_secs()),
+        }
+    }
+
+    fn lookup(&self, trigger: &str) -> std::result::Result<&'static str, String> {
+        let name = parse_trigger_name(trigger)?;
+        self.names.iter()
+            .copied()
+            .find(|n| *n == name)
+            .ok_or_else(|| format!("unknown trigger '{}' (expected {})", trigger, self.names.join(", ")))
+    }
+
+    async fn arm(&mut self, trigger: &str) -> std::result::Result<(), String> {
+        let name = self.lookup(trigger)?;
+        if self.running.contains_key(name) {
+            return Err(format!("{} trigger is already armed", name));
+        }
+
+        let mut started = start_triggers(&self.registry, &[name], &self.config, &self.tx).await;
+        let trigger = started.pop()
+            .ok_or_else(|| format!("{} trigger is not enabled in the config", name))?;
+        self.disarmed.remove(name);
+        self.off_schedule.remove(name);
+
+        let health = trigger.health();
+        self.running.insert(name, trigger);
+        if health != Health::Up {
+            return Err(format!("{} trigger did not come up ({})", name, health));
+        }
+        log::warn!(trigger = name; "[!] {} trigger armed", name);
+        Ok(())
+    }
+
+    async fn disarm(&mut self, trigger: &str) -> std::result::Result<(), String> {
+        let name = self.lookup(trigger)?;
+        let trigger = self.running.remove(name)
+            .ok_or_else(|| format!("{} trigger is not armed", name))?;
+
+        trigger.stop().await;
+        self.disarmed.insert(name);
+        log::warn!(trigger = name; "[!] {} trigger disarmed", name);
+        Ok(())
+    }
+
+    /// Disarming and pausing need the PIN from `arming.pin_hash`. Too many
+    /// wrong ones in a row lock PIN requests out for a while.
+    fn check_pin(&mut self, pin: Option<&Pin>, peer: Peer) -> std::result::Result<(), String> {
+        let hash = self.config.arming.pin_hash.as_deref()
+            .ok_or("no PIN configured (arming.pin_hash)")?;
+        let pin = pin.ok_or("PIN required")?;
+
+        let now = std::time::Instant::now();
+        if let Err(left) = self.pin_attempts.check(now) {
+            log::warn!("[!] PIN from uid {} not checked: locked out for {}s", peer.uid, left);
+            return Err(format!("too many wrong PINs, try again in {}s", left));
+        }
+        if pin::verify(hash, pin) {
+            self.pin_attempts.succeeded();
+            Ok(())
+        } else {
+            log::warn!("[!] Wrong PIN from uid {}", peer.uid);
+            if self.pin_attempts.failed(now) {
+                log::error!("[!] {} wrong PINs in a row, refusing PINs for {}s",
+                            pin::MAX_ATTEMPTS, pin::LOCKOUT.as_secs());
+            }
+            Err("wrong PIN".into())
+        }
+    }
+
+    async fn handle(&mut self, request: Request, peer: Peer) -> Response {
+        let (name, uid, pid) = (request_name(&request), peer.uid, peer.pid);
+        let response = self.dispatch(request, peer).await;
+        audit::record("control", json!({
+            "request": name, "uid": uid, "pid": pid, "ok": response.ok, "error": response.error,
+        }));
+        response
+    }
+
+    async fn dispatch(&mut self, request: Request, peer: Peer) -> Response {
+        let reason = format!("control socket, uid {}", peer.uid);
+        let result = match request {
+            Request::Status => return Response::status(self.status()),
+            Request::Arm { trigger: Some(trigger) } => self.arm(&trigger).await,
+            Request::Arm { trigger: None } => self.state.arm(&reason)
+                .map(|()| self.announce(true)),
+            Request::Disarm { trigger, pin } => match self.check_pin(pin.as_ref(), peer) {
+                Err(e) => Err(e),
+                Ok(()) => match trigger {
+                    Some(trigger) => self.disarm(&trigger).await,
+                    None => self.state.disarm(&reason)
+                        .map(|()| ActionExecutor::notify("DMS disarmed")),
+                },
+            },
+            Request::Pause { seconds, pin } => self.check_pin(pin.as_ref(), peer).and_then(|()| {
+                let until = Utc::now() + chrono::Duration::seconds(seconds.min(i64::MAX as u64) as i64);
+                self.state.pause(until, &reason)?;
+                ActionExecutor::notify(&format!("DMS {}", self.state.state()));
+                Ok(())
+            }),
+            Request::Fire => match self.state.state() {
+                SwitchState::Triggered => Err("switch is triggered; its actions are already running".into()),
+                _ => {
+                    log::warn!("[!] Control socket trigger from uid {}", peer.uid);
+                    let mut event = TriggerEvent::new(TriggerSource::Manual)
+                        .with("via", "control")
+                        .with("uid", peer.uid);
+                    if let Some(pid) = peer.pid {
+                        event = event.with("pid", pid);
+                    }
+                    self.tx.send(event).map_err(|e| e.to_string())
+                }
+            },
+            Request::Reload => {
+                log::warn!("[!] Reload requested over the control socket");
+                let result = self.reload().await.map_err(|e| e.to_string());
+                if result.is_ok() {
+                    log::info!("[+] Config reloaded");
+                }
+                result
+            }
+        };
+
+        match result {
+            Ok(()) => Response::ok(),
+            Err(e) => Response::error(e),
+        }
+    }
+}
+
+/// The command and target of a request, without the PIN.
+fn request_name(request: &Request) -> String {
+    match request {
+        Request::Status => "status".into(),
+        Request::Arm { trigger } => format!("arm {}", trigger.as_deref().unwrap_or("")).trim_end().into(),
+        Request::Disarm { trigger, .. } => format!("disarm {}", trigger.as_deref().unwrap_or("")).trim_end().into(),
+        Request::Pause { seconds, .. } => format!("pause {}s", seconds),
+        Request::Fire => "fire".into(),
+        Request::Reload => "reload".into(),
+    }
+}
+
+fn grace_period(config: &Config) -> std::time::Duration {
+    std::time::Duration::from_secs(config.arming.grace_period)
+}
+
+/// Resolves at `deadline`, or never if there is none.
+async fn sleep_until(deadline: Option<DateTime<Utc>>) {
+    match deadline {
+        Some(deadline) => {
+            let remaining = (deadline - Utc::now()).to_std().unwrap_or_default();
+            tokio::time::sleep(remaining).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once the running profile is done, or never if there is none.
+async fn profile_done(running: &mut Option<(AfterProfile, tokio::task::JoinHandle<bool>)>) -> bool {
+    match running {
+        Some((_, handle)) => handle.await.unwrap_or(false),
+        None => std::future::pending().await,
+    }
+}
+
+/// Arms every enabled trigger in `registry` and waits for events. The event
+/// and the action progress are reported on `progress`. Returns once a profile
+/// that exits has been started, or right away if no trigger is enabled; after
+/// any other profile the switch is re-armed or disarmed as it says.
+/// Reloads the config from `source` on SIGHUP, and serves the control socket.
+/// Returns on SIGTERM or Ctrl-C, after writing the audit checkpoint.
+pub async fn run_monitors(
+    registry: TriggerRegistry,
+    source: ConfigSource,
+    config: Config, 
+    progress: ProgressSender,
+) -> Result<()> {
+    registry.validate(&config)?;
+    if let Err(e) = audit::open(&config.audit) {
+        log::error!("[!] Audit log unavailable: {}", e);
+    }
+
+    let names: Vec<&'static str> = registry.names().collect();
+    let (tx, mut rx) = create_trigger_channel();
+
+    // Triggers outside their schedule are not started
+    let schedules = schedule::parse_all(&config.schedules)?;
+    let now = Local::now().naive_local();
+    let scheduled: HashMap<&'static str, bool> = names.iter()
+        .map(|name| (*name, schedules.get(*name).is_none_or(|s| s.is_active(now))))
+        .collect();
+    let off_schedule: HashSet<&'static str> = names.iter()
+        .copied()
+        .filter(|name| !scheduled[name] && registry.build(name, &config).is_some())
+        .collect();
+    for name in &off_schedule {
+        log::warn!(trigger = name; "[!] {} trigger not armed: outside its schedule ({})", name, schedules[*name]);
+    }
+
+    let start: Vec<&'static str> = names.iter()
+        .copied()
+        .filter(|name| !off_schedule.contains(name))
+        .collect();
+    let running: HashMap<&'static str, RunningTrigger> =
+        start_triggers(&registry, &start, &config, &tx).await
+            .into_iter()
+            .map(|t| (t.name, t))
+            .collect();
+
+    if running.is_empty() && off_schedule.is_empty() {
+        log::error!("[!] No triggers enabled");
+        return Ok(());
+    }
+
+    let mut switch = Switch {
+        executor: ActionExecutor::new(config.clone()),
+        state: StateMachine::new(grace_period(&config)),
+        pin_attempts: pin::Attempts::default(),
+        rules: RuleEngine::new(&config.rules),
+        registry,
+        names,
+        source,
+        config,
+        running,
+        disarmed: HashSet::new(),
+        schedules,
+        off_schedule,
+        scheduled,
+        countdown: None,
+        tx,
+    };
+
+    switch.announce(true);
+
+    let mut reload = ReloadSignal::new()?;
+    let mut stop = StopSignal::new()?;
+    let mut control = ControlServer::bind(&switch.config.control).unwrap_or_else(|e| {
+        log::error!("[!] Control socket unavailable: {}", e);
+        ControlServer::disabled()
+    });
+
+    // The profile being run and what to do once it is done
+    let mut running: Option<(AfterProfile, tokio::task::JoinHandle<bool>)> = None;
+    let mut schedule_tick = tokio::time::interval(SCHEDULE_TICK);
+    schedule_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            Some(event) = rx.recv() => {
+                let data = audit::event_data(&event);
+                // `ctl fire` is explicit: the grace period, a pause or a disarm
+                // do not hold it back
+                let manual = event.source == TriggerSource::Manual;
+                let state = switch.state.state();
+                if *state == SwitchState::Triggered || (!manual && *state != SwitchState::Armed) {
+                    log::warn!(trigger:% = event.source; "[!] Ignoring trigger from {}: switch is {}", event, switch.state.state());
+                    audit::record("event", json!({ "event": data, "outcome": "ignored", "state": switch.state.state().to_string() }));
+                    continue;
+                }
+                let Some(event) = switch.rules.evaluate(event) else {
+                    audit::record("event", json!({ "event": data, "outcome": "recorded by rules" }));
+                    continue;
+                };
+                audit::record("event", json!({ "event": data, "outcome": "fired" }));
+                let reason = format!("trigger from {}", event.source);
+                if manual {
+                    switch.state.fire(&reason);
+                } else {
+                    switch.state.trigger(&reason);
+                }
+                log::warn!(trigger:% = event.source; "[!] Trigger from {}", event);
+                let profile = policy::select(&switch.config, event.source);
+                switch.alert(&event, &profile).await;
+                let after = profile.after;
+                let handle = switch.executor.execute(&event, progress.clone());
+                if after == AfterProfile::Exit {
+                    return Ok(());
+                }
+                running = Some((after, tokio::task::spawn_blocking(move || handle.join().unwrap_or(false))));
+            }
+            completed = profile_done(&mut running) => {
+                let Some((after, _)) = running.take() else { continue };
+                let reason = if completed { "profile done" } else { "profile failed" };
+                if switch.state.resume(after == AfterProfile::Arm, reason).is_ok() {
+                    switch.rules.clear();
+                    switch.announce(true);
+                }
+            }
+            _ = sleep_until(switch.state.deadline()) => {
+                if switch.state.advance().is_some() {
+                    switch.announce(true);
+                }
+            }
+            _ = tokio::time::sleep(COUNTDOWN_TICK), if switch.countdown.is_some() => {
+                switch.announce(false);
+            }
+            _ = schedule_tick.tick(), if !switch.schedules.is_empty() => {
+                switch.apply_schedules().await;
+            }
+            _ = stop.recv() => {
+                log::warn!("[!] Stop requested, exiting");
+                audit::record_sync("stop", json!({}));
+                return Ok(());
+            }
+            _ = reload.recv() => {
+                log::warn!("[!] Reload requested");
+                match switch.reload().await {
+                    Ok(()) => log::info!("[+] Config reloaded"),
+                    Err(e) => log::error!("[!] Reload rejected, keeping current config: {}", e),
+                }
+            }
+            req = control.recv() => {
+                let response = switch.handle(req.request.clone(), req.peer).await;
+                req.respond(response);
+                // Rules only combine events seen while continuously armed
+                if *switch.state.state() != SwitchState::Armed {
+                    switch.rules.clear();
+                }
+            }
+        }
+    }
+}
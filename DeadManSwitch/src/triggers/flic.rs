@@ -1,32 +1,52 @@
 use flic_rust_client::*;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
 use tokio::time::{sleep, Duration};
 use crate::config::Config;
-use crate::error::{DmsError, Result};
-use super::{TriggerEvent, TriggerSender, TriggerSource};
+use crate::error::Result;
+use super::{Trigger, TriggerContext, TriggerEvent, TriggerFuture, TriggerSource};
 
+#[derive(Clone)]
 pub struct FlicMonitor {
     config: Config,
-    trigger_tx: TriggerSender,
+}
+
+impl Trigger for FlicMonitor {
+    fn name(&self) -> &'static str {
+        "flic"
+    }
+
+    fn start(&self, ctx: TriggerContext) -> TriggerFuture {
+        Box::pin(self.clone().run(ctx))
+    }
 }
 
 impl FlicMonitor {
-    pub fn new(config: Config, trigger_tx: TriggerSender) -> Self {
-        Self { config, trigger_tx }
+    pub fn new(config: Config) -> Self {
+        Self { config }
     }
 
-    pub async fn start(self) -> Result<()> {
+    async fn run(self, ctx: TriggerContext) -> Result<()> {
         let (info_tx, mut info_rx) = mpsc::channel(1);
-        let trigger_tx = self.trigger_tx.clone();
+        let trigger_tx = ctx.trigger_tx.clone();
+        // Button addresses by connection channel (conn_id - 1)
+        let buttons: Arc<Mutex<Vec<String>>> = Arc::default();
+        let buttons_handler = Arc::clone(&buttons);
 
         let handler = event_handler(move |event| {
             match event {
                 Event::ButtonSingleOrDoubleClickOrHold { 
-                    click_type: ClickType::ButtonHold, .. 
+                    conn_id, click_type: ClickType::ButtonHold, .. 
                 } => {
-                    log::warn!("[!] Flic trigger activated");
-                    let _ = trigger_tx.send(TriggerEvent::new(TriggerSource::Flic));
+                    log::warn!(trigger = "flic"; "[!] Flic trigger activated");
+                    let mut event = TriggerEvent::new(TriggerSource::Flic).with("conn_id", conn_id);
+                    let addr = buttons_handler.lock().unwrap()
+                        .get((*conn_id as usize).wrapping_sub(1))
+                        .cloned();
+                    if let Some(addr) = addr {
+                        event = event.with("button", addr);
+                    }
+                    let _ = trigger_tx.send(event);
                 }
                 Event::GetInfoResponse { bd_addr_of_verified_buttons, .. } => {
                     let _ = info_tx.try_send(bd_addr_of_verified_buttons.clone());
@@ -35,20 +55,23 @@ impl FlicMonitor {
             }
         });
 
-        let addr = format!("{}:{}", self.config.flic_ip, self.config.flic_port);
+        let addr = format!("{}:{}", self.config.triggers.flic.ip, self.config.triggers.flic.port);
         let client = Arc::new(
             FlicClient::new(&addr).await?
                 .register_event_handler(handler).await
         );
+        ctx.ready();
 
+        // Not spawned: both futures are dropped together with this task
         let client_cmd = Arc::clone(&client);
-        let cmd_task = tokio::spawn(async move {
+        let cmd_task = async move {
             client_cmd.submit(Command::CreateScanWizard { scan_wizard_id: 1 }).await;
             sleep(Duration::from_secs(5)).await;
             client_cmd.submit(Command::GetInfo).await;
 
-            if let Some(buttons) = info_rx.recv().await {
-                for (idx, addr) in buttons.iter().enumerate() {
+            if let Some(verified) = info_rx.recv().await {
+                *buttons.lock().unwrap() = verified.clone();
+                for (idx, addr) in verified.iter().enumerate() {
                     client_cmd.submit(Command::CreateConnectionChannel {
                         conn_id: (idx as u32) + 1,
                         bd_addr: addr.clone(),
@@ -58,15 +81,14 @@ impl FlicMonitor {
                     log::info!("[+] Flic armed: {}", addr);
                 }
             }
-        });
+        };
 
         let client_listen = Arc::clone(&client);
-        let listen_task = tokio::spawn(async move {
+        let listen_task = async move {
             client_listen.listen().await;
-        });
+        };
 
-        let _ = tokio::try_join!(cmd_task, listen_task)
-            .map_err(|e| DmsError::Join(e.to_string()))?;
+        tokio::join!(cmd_task, listen_task);
         
         client.stop().await;
         Ok(())
@@ -3,8 +3,20 @@ pub mod telegram;
 pub mod usb;
 pub mod flic;
 pub mod timer;  // ← NEW
+pub mod registry;
+pub mod supervisor;
 
-use tokio::sync::mpsc;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot, watch};
+use crate::error::Result;
+
+pub use registry::TriggerRegistry;
+pub use supervisor::RunningTrigger;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TriggerSource {
@@ -13,27 +25,187 @@ pub enum TriggerSource {
     Usb,
     Flic,
     Timer,
+    /// `--trigger` on the command line
+    Manual,
+    /// A compound rule, see `rules`
+    Rule,
+    /// A trigger registered outside this crate, by name
+    Other(&'static str),
 }
 
+impl std::fmt::Display for TriggerSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TriggerSource::Network => f.write_str("network"),
+            TriggerSource::Telegram => f.write_str("telegram"),
+            TriggerSource::Usb => f.write_str("usb"),
+            TriggerSource::Flic => f.write_str("flic"),
+            TriggerSource::Timer => f.write_str("timer"),
+            TriggerSource::Manual => f.write_str("manual"),
+            TriggerSource::Rule => f.write_str("rule"),
+            TriggerSource::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+/// What fired, when (UTC), on which host, plus source-specific details such
+/// as the USB device ids, the UDP peer or the Telegram chat.
 #[derive(Debug, Clone)]
 pub struct TriggerEvent {
     pub source: TriggerSource,
-    #[allow(dead_code)]
-    pub timestamp: std::time::Instant,
+    pub time: DateTime<Utc>,
+    pub hostname: String,
+    pub details: BTreeMap<String, String>,
+    /// Raised under `--dry-run`: nothing destructive is executed
+    pub test: bool,
 }
 
 impl TriggerEvent {
     pub fn new(source: TriggerSource) -> Self {
         Self {
             source,
-            timestamp: std::time::Instant::now(),
+            time: Utc::now(),
+            hostname: hostname(),
+            details: BTreeMap::new(),
+            test: false,
+        }
+    }
+
+    /// Adds a detail entry.
+    pub fn with(mut self, key: &str, value: impl ToString) -> Self {
+        self.details.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// "key=value, ..." of the details, empty if there are none.
+    pub fn details_line(&self) -> String {
+        self.details.iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl std::fmt::Display for TriggerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} on {} at {}", self.source, self.hostname,
+               self.time.to_rfc3339_opts(SecondsFormat::Secs, true))?;
+        if !self.details.is_empty() {
+            write!(f, " ({})", self.details_line())?;
         }
+        if self.test {
+            f.write_str(" [TEST]")?;
+        }
+        Ok(())
     }
 }
 
+pub fn hostname() -> String {
+    whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string())
+}
+
 pub type TriggerSender = mpsc::UnboundedSender<TriggerEvent>;
 pub type TriggerReceiver = mpsc::UnboundedReceiver<TriggerEvent>;
 
 pub fn create_trigger_channel() -> (TriggerSender, TriggerReceiver) {
     mpsc::unbounded_channel()
-}
\ No newline at end of file
+}
+
+pub type TriggerFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// A monitor that fires `TriggerEvent`s. Built from the config by a
+/// `TriggerRegistry` entry.
+pub trait Trigger: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Runs the monitor until it stops or fails. It keeps running after firing,
+    /// as the switch ignores events while disarmed or paused. Implementations
+    /// call `ctx.ready()` once they are actually listening. May be called again
+    /// to restart the monitor.
+    fn start(&self, ctx: TriggerContext) -> TriggerFuture;
+
+    /// Time until the trigger fires on its own, for triggers that count
+    /// down (the heartbeat timer).
+    fn remaining(&self) -> Option<std::time::Duration> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Starting,
+    Up,
+    Restarting,
+    Failed(String),
+}
+
+impl std::fmt::Display for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Health::Starting => f.write_str("starting"),
+            Health::Up => f.write_str("up"),
+            Health::Restarting => f.write_str("restarting"),
+            Health::Failed(_) => f.write_str("failed"),
+        }
+    }
+}
+
+/// Outcome of a trigger's first start: `Ok` once it called `ready()`, or the
+/// reason its first attempt ended before that.
+pub type StartupResult = std::result::Result<(), String>;
+
+/// One-shot startup report, shared by every attempt of a supervised trigger.
+/// Only the first report is delivered.
+#[derive(Clone)]
+pub struct StartupReport(Arc<Mutex<Option<oneshot::Sender<StartupResult>>>>);
+
+impl StartupReport {
+    pub fn new() -> (Self, oneshot::Receiver<StartupResult>) {
+        let (tx, rx) = oneshot::channel();
+        (Self(Arc::new(Mutex::new(Some(tx)))), rx)
+    }
+
+    pub fn report(&self, result: StartupResult) {
+        if let Some(tx) = self.0.lock().unwrap().take() {
+            let _ = tx.send(result);
+        }
+    }
+}
+
+/// Handed to `Trigger::start`.
+pub struct TriggerContext {
+    pub trigger_tx: TriggerSender,
+    health: watch::Sender<Health>,
+    startup: StartupReport,
+}
+
+impl TriggerContext {
+    /// Marks the trigger as armed and completes the startup handshake.
+    pub fn ready(&self) {
+        self.health.send_replace(Health::Up);
+        self.startup.report(Ok(()));
+    }
+}
+
+/// Tells a blocking monitor loop (running on `spawn_blocking`) to exit once
+/// the async task that owns it is dropped, e.g. when aborted on reload.
+#[derive(Clone, Default)]
+pub struct StopFlag(Arc<AtomicBool>);
+
+impl StopFlag {
+    pub fn is_set(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn guard(&self) -> StopGuard {
+        StopGuard(self.clone())
+    }
+}
+
+pub struct StopGuard(StopFlag);
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        (self.0).0.store(true, Ordering::Relaxed);
+    }
+}
@@ -1,58 +1,112 @@
+use serde_json::json;
 use std::net::{SocketAddr, UdpSocket};
-use tokio::task;
+use std::time::{Duration, Instant};
+use crate::audit;
 use crate::config::Config;
 use crate::error::Result;
-use super::{TriggerEvent, TriggerSender, TriggerSource};
+use super::{Trigger, TriggerContext, TriggerEvent, TriggerFuture, TriggerSource};
 
+/// Packets that are not the trigger message are recorded once per window.
+const IGNORED_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 pub struct NetworkListener {
     config: Config,
-    trigger_tx: TriggerSender,
 }
 
-impl NetworkListener {
-    pub fn new(config: Config, trigger_tx: TriggerSender) -> Self {
-        Self { config, trigger_tx }
+impl Trigger for NetworkListener {
+    fn name(&self) -> &'static str {
+        "network"
+    }
+
+    fn start(&self, ctx: TriggerContext) -> TriggerFuture {
+        Box::pin(self.clone().run(ctx))
     }
+}
 
-    pub async fn start(self) -> Result<()> {
-        task::spawn_blocking(move || self.run()).await
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+impl NetworkListener {
+    pub fn new(config: Config) -> Self {
+        Self { config }
     }
 
-    fn run(self) -> Result<()> {
-        let addr: SocketAddr = format!("0.0.0.0:{}", self.config.broadcast_port).parse()
+    // Async socket so that aborting the task (e.g. on reload) releases the
+    // port right away.
+    async fn run(self, ctx: TriggerContext) -> Result<()> {
+        let addr: SocketAddr = format!("0.0.0.0:{}", self.config.triggers.network.port).parse()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
         
-        let socket = UdpSocket::bind(addr)?;
-        socket.set_nonblocking(false)?;
+        let socket = tokio::net::UdpSocket::bind(addr).await?;
         
         log::warn!("[!] Network trigger: {}", addr);
+        ctx.ready();
         
         let mut buf = vec![0u8; 4096];
+        let mut window: Option<Instant> = None;
+        let mut ignored = 0u64;
         loop {
-            match socket.recv_from(&mut buf) {
-                Ok((size, _)) => {
+            match socket.recv_from(&mut buf).await {
+                Ok((size, peer)) => {
                     let msg = String::from_utf8_lossy(&buf[..size]);
-                    if msg.eq_ignore_ascii_case(&self.config.broadcast_message) {
-                        log::warn!("[!] Network trigger activated");
-                        let _ = self.trigger_tx.send(TriggerEvent::new(TriggerSource::Network));
-                        break;
+                    let mut lines = msg.lines();
+                    let first = lines.next().unwrap_or_default().trim();
+                    let matched = first.eq_ignore_ascii_case(&self.config.triggers.network.message);
+                    // Anyone can send packets, so other traffic is recorded
+                    // once per window and counted otherwise
+                    let quiet = !matched && window.is_some_and(|start| start.elapsed() < IGNORED_WINDOW);
+                    if quiet {
+                        ignored += 1;
+                    } else {
+                        if !matched {
+                            if ignored > 0 {
+                                audit::record("udp_packets_ignored", json!({ "count": ignored }));
+                                ignored = 0;
+                            }
+                            window = Some(Instant::now());
+                        }
+                        audit::record("udp_packet", json!({
+                            "peer": peer.to_string(),
+                            "size": size,
+                            "first_line": first.chars().take(64).collect::<String>(),
+                            "matched": matched,
+                        }));
+                    }
+                    if matched {
+                        let mut event = TriggerEvent::new(TriggerSource::Network).with("peer", peer);
+                        // Anything else the sender put after the message is not trusted
+                        event.test = lines.any(|l| l.split_once('=')
+                            .is_some_and(|(key, value)| key.trim() == "test" && value.trim() == "true"));
+
+                        // A dry run elsewhere must not fire this machine for real
+                        if event.test && !self.config.dry_run {
+                            log::warn!("[!] Ignoring test broadcast from {}", peer);
+                            continue;
+                        }
+                        log::warn!(trigger = "network"; "[!] Network trigger activated by {}", peer);
+                        let _ = ctx.trigger_tx.send(event);
                     }
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
                 Err(e) => return Err(e.into()),
             }
         }
-        Ok(())
     }
 
-    pub fn send_trigger_broadcast(config: &Config) -> Result<()> {
-        let addr: SocketAddr = format!("255.255.255.255:{}", config.broadcast_port).parse()
+    /// Broadcasts the bare trigger message, which every listener matches on,
+    /// whatever its version. A dry run appends a `test=true` line, so that
+    /// only listeners that understand it see a match (and then ignore it
+    /// unless they are in a dry run too). Nothing about the event is sent.
+    pub fn send_trigger_broadcast(config: &Config, event: &TriggerEvent) -> Result<()> {
+        let mut payload = config.triggers.network.message.clone();
+        if event.test {
+            payload.push_str("\ntest=true\n");
+        }
+
+        let addr: SocketAddr = format!("255.255.255.255:{}", config.triggers.network.port).parse()
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
         
         let socket = UdpSocket::bind("0.0.0.0:0")?;
         socket.set_broadcast(true)?;
-        socket.send_to(config.broadcast_message.as_bytes(), addr)?;
+        socket.send_to(payload.as_bytes(), addr)?;
         
         log::warn!("[!] Broadcast trigger sent");
         Ok(())
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use crate::config::Config;
+use crate::error::{DmsError, Result};
+use super::{flic, network, telegram, timer, usb, Trigger};
+
+type BuildFn = dyn Fn(&Config) -> Option<Arc<dyn Trigger>> + Send + Sync;
+type ChangedFn = dyn Fn(&Config, &Config) -> bool + Send + Sync;
+
+struct Entry {
+    name: &'static str,
+    build: Box<BuildFn>,
+    changed: Box<ChangedFn>,
+}
+
+/// The set of known triggers, in start order.
+///
+/// Each entry builds its trigger from the config (or returns `None` when the
+/// trigger is disabled) and says whether a config change requires a restart.
+#[derive(Default)]
+pub struct TriggerRegistry {
+    entries: Vec<Entry>,
+}
+
+impl TriggerRegistry {
+    /// Registry with all triggers shipped in this crate.
+    pub fn builtin() -> Self {
+        let mut registry = Self::default();
+
+        registry.register(
+            "timer",
+            |c| c.triggers.timer.enabled.then(|| Arc::new(timer::HeartbeatTimer::new(c.clone())) as _),
+            |old, new| old.telegram_bot_token != new.telegram_bot_token
+                || old.triggers.timer != new.triggers.timer,
+        );
+        registry.register(
+            "telegram",
+            |c| c.triggers.telegram.enabled.then(|| Arc::new(telegram::TelegramListener::new(c.clone())) as _),
+            |old, new| old.telegram_bot_token != new.telegram_bot_token
+                || old.triggers.telegram != new.triggers.telegram,
+        );
+        registry.register(
+            "network",
+            |c| c.triggers.network.enabled.then(|| Arc::new(network::NetworkListener::new(c.clone())) as _),
+            |old, new| old.triggers.network != new.triggers.network,
+        );
+        registry.register(
+            "usb",
+            |c| c.triggers.usb.enabled.then(|| Arc::new(usb::UsbMonitor::new(c.clone())) as _),
+            |old, new| old.triggers.usb != new.triggers.usb,
+        );
+        registry.register(
+            "flic",
+            |c| c.triggers.flic.enabled.then(|| Arc::new(flic::FlicMonitor::new(c.clone())) as _),
+            |old, new| old.triggers.flic != new.triggers.flic,
+        );
+
+        registry
+    }
+
+    /// Adds a trigger. `build` returns `None` when the trigger is disabled in
+    /// the given config; `changed` reports whether a config change affects it.
+    pub fn register<B, C>(&mut self, name: &'static str, build: B, changed: C)
+    where
+        B: Fn(&Config) -> Option<Arc<dyn Trigger>> + Send + Sync + 'static,
+        C: Fn(&Config, &Config) -> bool + Send + Sync + 'static,
+    {
+        self.entries.retain(|e| e.name != name);
+        self.entries.push(Entry { name, build: Box::new(build), changed: Box::new(changed) });
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.entries.iter().map(|e| e.name)
+    }
+
+    /// Builds the named trigger, or `None` if it is unknown or disabled.
+    pub fn build(&self, name: &str, config: &Config) -> Option<Arc<dyn Trigger>> {
+        self.entry(name).and_then(|e| (e.build)(config))
+    }
+
+    /// Whether the named trigger has to be (re)started or stopped to pick up
+    /// `new`, including being enabled or disabled.
+    pub fn changed(&self, name: &str, old: &Config, new: &Config) -> bool {
+        let Some(entry) = self.entry(name) else {
+            return false;
+        };
+        (entry.build)(old).is_some() != (entry.build)(new).is_some()
+            || (entry.changed)(old, new)
+    }
+
+    /// Rejects trigger sections, schedules, policy sources and rule sources
+    /// naming a trigger that is not registered: they would never apply.
+    pub fn validate(&self, config: &Config) -> Result<()> {
+        let referenced = config.triggers.other.keys()
+            .map(|name| (format!("triggers.{}", name), name))
+            .chain(config.schedules.keys().map(|name| (format!("schedules.{}", name), name)))
+            .chain(config.policy.sources.keys()
+                .filter(|source| *source != "manual" && *source != "rule")
+                .map(|source| (format!("policy.sources.{}", source), source)))
+            .chain(config.rules.iter()
+                .flat_map(|rule| rule.sources.iter().map(move |source| (format!("rules.{}", rule.name), source))));
+        for (key, name) in referenced {
+            if self.entry(name).is_none() {
+                return Err(DmsError::Config(format!("{}: unknown trigger '{}' (expected {})",
+                                                    key, name, self.names().collect::<Vec<_>>().join(", "))));
+            }
+        }
+        Ok(())
+    }
+
+    fn entry(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unknown_trigger_names() {
+        let config = |toml: &str| Config::from_toml(toml).unwrap();
+        let registry = TriggerRegistry::builtin();
+        assert!(registry.validate(&config(r#"
+            schedules = { usb = ["mon-fri 09:00-17:00"] }
+            [policy]
+            sources = { manual = "full", rule = "full", network = "full" }
+            [[rules]]
+            name = "both"
+            kind = "all"
+            sources = ["usb", "timer"]
+            window = 60
+        "#)).is_ok());
+
+        let error = |toml: &str| registry.validate(&config(toml)).unwrap_err().to_string();
+        assert!(error(r#"schedules = { ubs = ["09:00-17:00"] }"#).contains("schedules.ubs: unknown trigger 'ubs'"));
+        assert!(error("[policy]\nsources = { netwrok = \"full\" }").contains("policy.sources.netwrok"));
+        assert!(error("[[rules]]\nname = \"r\"\nkind = \"any\"\nsources = [\"flik\"]").contains("rules.r: unknown trigger 'flik'"));
+
+        assert!(error("[triggers.gpio]\nenabled = true").contains("triggers.gpio: unknown trigger 'gpio'"));
+
+        // Registered out-of-tree triggers are known, and read their section
+        #[derive(serde::Deserialize, Default)]
+        struct GpioConfig {
+            enabled: bool,
+            pin: u8,
+        }
+        let mut registry = TriggerRegistry::builtin();
+        registry.register("gpio", |_| None, |_, _| false);
+        let gpio = config("schedules = { gpio = [\"09:00-17:00\"] }\n[triggers.gpio]\nenabled = true\npin = 17");
+        assert!(registry.validate(&gpio).is_ok());
+        let section: GpioConfig = gpio.triggers.section("gpio").unwrap();
+        assert!(section.enabled);
+        assert_eq!(section.pin, 17);
+        assert!(!config("").triggers.section::<GpioConfig>("gpio").unwrap().enabled);
+    }
+}
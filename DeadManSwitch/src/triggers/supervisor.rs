@@ -0,0 +1,130 @@
+use serde_json::json;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use crate::actions::ActionExecutor;
+use crate::audit;
+use crate::config::SupervisorConfig;
+use super::{Health, StartupReport, StartupResult, Trigger, TriggerContext, TriggerSender};
+
+/// A supervised trigger. The supervisor restarts the trigger with exponential
+/// backoff whenever it exits, fails or panics, up to `max_restarts` times.
+/// Dropping it does not stop the task; call `stop`.
+pub struct RunningTrigger {
+    pub name: &'static str,
+    trigger: Arc<dyn Trigger>,
+    task: JoinHandle<()>,
+    health: watch::Receiver<Health>,
+    startup: Option<oneshot::Receiver<StartupResult>>,
+}
+
+impl RunningTrigger {
+    pub fn spawn(trigger: Arc<dyn Trigger>, trigger_tx: TriggerSender, policy: SupervisorConfig) -> Self {
+        let name = trigger.name();
+        let (health_tx, health) = watch::channel(Health::Starting);
+        let (report, startup) = StartupReport::new();
+        let task = tokio::spawn(supervise(Arc::clone(&trigger), trigger_tx, policy, health_tx, report));
+
+        Self { name, trigger, task, health, startup: Some(startup) }
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.borrow().clone()
+    }
+
+    /// See `Trigger::remaining`. `None` unless the trigger is up.
+    pub fn remaining(&self) -> Option<Duration> {
+        (self.health() == Health::Up).then(|| self.trigger.remaining()).flatten()
+    }
+
+    /// Waits until the trigger reports readiness or its first attempt fails,
+    /// but no later than `deadline`. A trigger that misses the deadline keeps
+    /// running under the supervisor and may still come up later.
+    pub async fn wait_ready(&mut self, deadline: tokio::time::Instant) -> StartupResult {
+        let Some(startup) = self.startup.take() else {
+            return match self.health() {
+                Health::Up => Ok(()),
+                other => Err(other.to_string()),
+            };
+        };
+
+        match tokio::time::timeout_at(deadline, startup).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err("supervisor stopped".to_string()),
+            Err(_) => Err("not ready within startup timeout".to_string()),
+        }
+    }
+
+    pub async fn stop(self) {
+        self.task.abort();
+        let _ = self.task.await;
+    }
+}
+
+/// Aborts the wrapped task when dropped, so that stopping the supervisor also
+/// stops the attempt it is waiting on.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn supervise(
+    trigger: Arc<dyn Trigger>,
+    trigger_tx: TriggerSender,
+    policy: SupervisorConfig,
+    health: watch::Sender<Health>,
+    startup: StartupReport,
+) {
+    let name = trigger.name();
+    let initial_backoff = Duration::from_secs(policy.backoff.max(1));
+    let max_backoff = Duration::from_secs(policy.max_backoff).max(initial_backoff);
+    let mut backoff = initial_backoff;
+    let mut restarts = 0;
+
+    loop {
+        let ctx = TriggerContext {
+            trigger_tx: trigger_tx.clone(),
+            health: health.clone(),
+            startup: startup.clone(),
+        };
+        let started = Instant::now();
+
+        // Each attempt runs in its own task so that a panic is caught here
+        let mut attempt = AbortOnDrop(tokio::spawn(trigger.start(ctx)));
+        let outcome = match (&mut attempt.0).await {
+            Ok(Ok(())) => "stopped".to_string(),
+            Ok(Err(e)) => format!("failed: {}", e),
+            Err(e) if e.is_panic() => "panicked".to_string(),
+            Err(e) => format!("was cancelled: {}", e),
+        };
+        // No-op unless the attempt ended before calling ready()
+        startup.report(Err(outcome.clone()));
+        audit::record("trigger_exit", json!({ "trigger": name, "outcome": outcome }));
+
+        // A run that outlasted the longest backoff counts as healthy
+        if started.elapsed() >= max_backoff {
+            restarts = 0;
+            backoff = initial_backoff;
+        }
+
+        if restarts >= policy.max_restarts {
+            log::error!(trigger = name; "[!] {} trigger {}, giving up after {} restarts", name, outcome, restarts);
+            health.send_replace(Health::Failed(outcome.clone()));
+            ActionExecutor::notify(&format!("{} trigger {} - no longer armed", name, outcome));
+            return;
+        }
+
+        restarts += 1;
+        log::warn!(trigger = name; "[!] {} trigger {}, restarting in {}s ({}/{})",
+                   name, outcome, backoff.as_secs(), restarts, policy.max_restarts);
+        health.send_replace(Health::Restarting);
+        ActionExecutor::notify(&format!("{} trigger {} - restarting", name, outcome));
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
@@ -1,8 +1,11 @@
+use serde_json::json;
 use teloxide::prelude::*;
 use teloxide::utils::command::BotCommands;
-use crate::config::Config;
-use crate::error::Result;
-use super::{TriggerEvent, TriggerSender, TriggerSource};
+use crate::audit;
+use crate::config::{ActionConfig, ActionKind, Config};
+use crate::error::{DmsError, Result};
+use crate::policy::Profile;
+use super::{Trigger, TriggerContext, TriggerEvent, TriggerFuture, TriggerSource};
 
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase")]
@@ -11,33 +14,44 @@ enum Command {
     Dms(String),
 }
 
+#[derive(Clone)]
 pub struct TelegramListener {
     config: Config,
-    trigger_tx: TriggerSender,
+}
+
+impl Trigger for TelegramListener {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn start(&self, ctx: TriggerContext) -> TriggerFuture {
+        Box::pin(self.clone().run(ctx))
+    }
 }
 
 impl TelegramListener {
-    pub fn new(config: Config, trigger_tx: TriggerSender) -> Self {
-        Self { config, trigger_tx }
+    pub fn new(config: Config) -> Self {
+        Self { config }
     }
 
-    pub async fn start(self) -> Result<()> {
-        if self.config.telegram_bot_token.contains("TOKEN") {
-            log::error!("[!] Invalid Telegram token");
-            return Err(crate::error::DmsError::Config("Invalid token".into()));
-        }
-        
-        let bot = Bot::new(&self.config.telegram_bot_token);
+    async fn run(self, ctx: TriggerContext) -> Result<()> {
+        let token = self.config.telegram_token().map_err(|e| {
+            log::error!("[!] Invalid Telegram token: {}", e);
+            e
+        })?;
+
+        let bot = Bot::new(token);
         
         if let Err(e) = bot.get_me().await {
             log::error!("[!] Telegram connection failed: {:?}", e);
-            return Err(crate::error::DmsError::Config(format!("Connection failed: {}", e)));
+            return Err(DmsError::Config(format!("Connection failed: {}", e)));
         }
         
         log::warn!("[!] Telegram manual trigger active");
+        ctx.ready();
 
-        let trigger_tx = self.trigger_tx.clone();
-        let expected_cmd = self.config.telegram_command.clone();
+        let trigger_tx = ctx.trigger_tx.clone();
+        let expected_cmd = self.config.triggers.telegram.command.clone();
         let bot_clone = bot.clone();
 
         let handler = Update::filter_channel_post()
@@ -52,10 +66,27 @@ impl TelegramListener {
                         async move {
                             let Command::Dms(param) = cmd;
                             if param == expected {
-                                log::warn!("[!] Manual Telegram trigger activated");
-                                let _ = bot.send_message(msg.chat.id, "🚨☠️ Dead Man Switch ACTIVATED! 🚨☠️").await;
-                                let _ = tx.send(TriggerEvent::new(TriggerSource::Telegram));
+                                log::warn!(trigger = "telegram"; "[!] Manual Telegram trigger activated from chat {}", msg.chat.id);
+                                let mut event = TriggerEvent::new(TriggerSource::Telegram)
+                                    .with("chat_id", msg.chat.id);
+                                if let Some(title) = msg.chat.title() {
+                                    event = event.with("chat", title);
+                                }
+                                if let Some(user) = msg.from() {
+                                    event = event.with("user_id", user.id);
+                                }
+                                if let Some(author) = msg.author_signature() {
+                                    event = event.with("author", author);
+                                }
+                                // The switch replies once it has accepted the event
+                                let _ = tx.send(event);
                             } else {
+                                // Not the parameter itself: a near miss would leak the secret
+                                audit::record("telegram_rejected", json!({
+                                    "chat_id": msg.chat.id.0,
+                                    "user_id": msg.from().map(|u| u.id.0),
+                                    "param_len": param.chars().count(),
+                                }));
                                 let _ = bot.send_message(msg.chat.id, "❌ Invalid command parameter").await;
                             }
                             respond(())
@@ -71,4 +102,94 @@ impl TelegramListener {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Prefix for Telegram alerts sent under `--dry-run`.
+pub(crate) fn test_banner(config: &Config) -> &'static str {
+    if config.dry_run { "🧪 TEST - dry run, no action taken\n\n" } else { "" }
+}
+
+/// Sends `text` to `telegram_chat_id`; does nothing if none is configured.
+pub async fn announce(config: &Config, text: &str) -> Result<()> {
+    match config.telegram_chat_id {
+        Some(chat_id) => send(config, chat_id, text).await,
+        None => Ok(()),
+    }
+}
+
+/// Sends `text` to `chat_id`.
+pub async fn send(config: &Config, chat_id: i64, text: &str) -> Result<()> {
+    Bot::new(config.telegram_token()?)
+        .send_message(ChatId(chat_id), text)
+        .await
+        .map_err(|e| DmsError::Telegram(e.to_string()))?;
+    Ok(())
+}
+
+/// The alert for an activation running `profile`, for the chat the event
+/// came from: the Telegram command's, or the one the timer's heartbeats came
+/// from, also when a rule combined them. `None` for events from elsewhere.
+pub fn activation_alert(config: &Config, event: &TriggerEvent, profile: &Profile) -> Option<(i64, String)> {
+    let detail = |key: &str| event.details.get(key).map(String::as_str);
+    let chat_id = detail("chat_id").or(detail("telegram_chat_id")).or(detail("timer_chat_id"))?.parse().ok()?;
+    let cause = match event.source {
+        TriggerSource::Timer => format!("⚠️ Heartbeat timeout exceeded: {} seconds\n", detail("elapsed_secs").unwrap_or("?")),
+        TriggerSource::Rule => format!("⚠️ Rule '{}' satisfied by {}\n",
+                                       detail("rule").unwrap_or("?"), detail("matched").unwrap_or("?")),
+        _ => String::new(),
+    };
+    let steps = profile.actions.iter().map(ActionConfig::name).collect::<Vec<_>>().join(", ");
+    let outcome = if profile.actions.iter().any(|a| a.kind == ActionKind::Shutdown) {
+        format!("💀 System shutdown initiated ({})", steps)
+    } else {
+        format!("🔒 Running profile '{}': {}", profile.name, steps)
+    };
+    let text = format!(
+        "{}🚨☠️ DEAD MAN SWITCH ACTIVATED! ☠️🚨\n\n\
+        {}\
+        🖥️ Host: {}\n\
+        🕒 {} UTC\n\
+        {}\n\n\
+        This is an automated security response.",
+        test_banner(config), cause, event.hostname, event.time.format("%Y-%m-%d %H:%M:%S"), outcome
+    );
+    Some((chat_id, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy;
+
+    fn config() -> Config {
+        Config::from_toml(r#"
+            [policy]
+            default = "lock"
+            sources = { timer = "full" }
+            [profiles.lock]
+            actions = [{ type = "lock_screen" }]
+        "#).unwrap()
+    }
+
+    #[test]
+    fn alert_follows_the_profile_run() {
+        let config = config();
+        let telegram = TriggerEvent::new(TriggerSource::Telegram).with("chat_id", -100);
+        let (chat_id, text) = activation_alert(&config, &telegram, &policy::select(&config, telegram.source)).unwrap();
+        assert_eq!(chat_id, -100);
+        assert!(text.contains("Running profile 'lock': lock_screen"), "{}", text);
+        assert!(!text.contains("shutdown"), "{}", text);
+
+        let timer = TriggerEvent::new(TriggerSource::Timer).with("chat_id", 7).with("elapsed_secs", 90);
+        let (_, text) = activation_alert(&config, &timer, &policy::select(&config, timer.source)).unwrap();
+        assert!(text.contains("timeout exceeded: 90 seconds"), "{}", text);
+        assert!(text.contains("System shutdown initiated"), "{}", text);
+
+        // A rule keeps the chat of the events it combined
+        let rule = TriggerEvent::new(TriggerSource::Rule).with("rule", "both").with("telegram_chat_id", -100);
+        assert_eq!(activation_alert(&config, &rule, &policy::select(&config, rule.source)).unwrap().0, -100);
+
+        let network = TriggerEvent::new(TriggerSource::Network);
+        assert!(activation_alert(&config, &network, &policy::select(&config, network.source)).is_none());
+    }
+}
@@ -1,11 +1,13 @@
+use serde_json::json;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::time::{sleep, Duration};
 use teloxide::prelude::*;
 use teloxide::utils::command::BotCommands;
+use crate::audit;
 use crate::config::Config;
 use crate::error::Result;
-use super::{TriggerEvent, TriggerSender, TriggerSource};
+use super::{Trigger, TriggerContext, TriggerEvent, TriggerFuture, TriggerSource};
 
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase")]
@@ -17,49 +19,68 @@ enum TimerCommand {
     Status,
 }
 
+#[derive(Clone)]
 pub struct HeartbeatTimer {
     config: Config,
-    trigger_tx: TriggerSender,
+    // Shared by all clones, so the running instance can be queried
+    last_heartbeat: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Trigger for HeartbeatTimer {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+
+    fn start(&self, ctx: TriggerContext) -> TriggerFuture {
+        Box::pin(self.clone().run(ctx))
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        let last = (*self.last_heartbeat.lock().unwrap())?;
+        Some(Duration::from_secs(self.config.triggers.timer.timeout).saturating_sub(last.elapsed()))
+    }
 }
 
 impl HeartbeatTimer {
-    pub fn new(config: Config, trigger_tx: TriggerSender) -> Self {
-        Self { config, trigger_tx }
+    pub fn new(config: Config) -> Self {
+        Self { config, last_heartbeat: Arc::default() }
     }
 
-    pub async fn start(self) -> Result<()> {
-        if self.config.telegram_bot_token.contains("TOKEN") {
-            log::error!("[!] Invalid Telegram token for timer");
-            return Err(crate::error::DmsError::Config("Invalid token".into()));
-        }
+    async fn run(self, ctx: TriggerContext) -> Result<()> {
+        let token = self.config.telegram_token().map_err(|e| {
+            log::error!("[!] Invalid Telegram token for timer: {}", e);
+            e
+        })?;
 
-        let bot = Bot::new(&self.config.telegram_bot_token);
+        let bot = Bot::new(token);
         
         if let Err(e) = bot.get_me().await {
             log::error!("[!] Telegram connection failed: {:?}", e);
             return Err(crate::error::DmsError::Config(format!("Connection failed: {}", e)));
         }
 
-        let timeout_duration = self.config.telegram_heartbeat_timeout;
-        let last_heartbeat = Arc::new(Mutex::new(Instant::now()));
+        let timeout_duration = self.config.triggers.timer.timeout;
+        *self.last_heartbeat.lock().unwrap() = Some(Instant::now());
+        let last_heartbeat = Arc::clone(&self.last_heartbeat);
         let last_chat_id: Arc<Mutex<Option<ChatId>>> = Arc::new(Mutex::new(None));  // ← Track chat ID
         
         log::warn!("[!] Heartbeat timer started: {} seconds timeout", timeout_duration);
         log::warn!("[!] Timer bot listening for /alive and /status");
+        ctx.ready();
 
-        // Spawn countdown monitor
-        let trigger_tx = self.trigger_tx.clone();
+        // Countdown monitor, raced against the dispatcher below so that both
+        // stop together when the task is aborted
+        let trigger_tx = ctx.trigger_tx.clone();
         let last_heartbeat_monitor = Arc::clone(&last_heartbeat);
         let last_chat_id_monitor = Arc::clone(&last_chat_id);
-        let bot_monitor = bot.clone();
         
-        tokio::spawn(async move {
+        let countdown = async move {
             loop {
                 sleep(Duration::from_secs(1)).await;
                 
                 let elapsed = {
                     let last = last_heartbeat_monitor.lock().unwrap();
-                    last.elapsed().as_secs()
+                    last.map_or(0, |t| t.elapsed().as_secs())
                 };
 
                 let remaining = timeout_duration.saturating_sub(elapsed);
@@ -70,33 +91,24 @@ impl HeartbeatTimer {
 
                 if elapsed >= timeout_duration {
                     log::error!("[!] Heartbeat timeout exceeded ({} seconds)", elapsed);
-                    log::error!("[!] No heartbeat received - triggering DMS");
+                    log::error!(trigger = "timer"; "[!] No heartbeat received - triggering DMS");
                     
-                    let chat_id_opt = {
-                        let mut guard = last_chat_id_monitor.lock().unwrap();
-                        guard.take()
-                    };
-                    // Send alert to Telegram if we have a chat_id
-                    if let Some(chat_id) = chat_id_opt {
-                        let message = format!(
-                            "🚨☠️ DEAD MAN SWITCH ACTIVATED! ☠️🚨\n\n\
-                            ⚠️ Heartbeat timeout exceeded: {} seconds\n\
-                            💀 System shutdown initiated\n\n\
-                            This is an automated security response.",
-                            elapsed
-                        );
-
-                        if let Err(e) = bot_monitor.send_message(chat_id, message).await {
-                            log::error!("[!] Failed to send Telegram alert: {:?}", e);
-                        }
+                    let mut event = TriggerEvent::new(TriggerSource::Timer)
+                        .with("timeout_secs", timeout_duration)
+                        .with("elapsed_secs", elapsed)
+                        .with("overdue_secs", elapsed - timeout_duration);
+                    // The switch alerts the chat the heartbeats came from,
+                    // if the event is acted on
+                    if let Some(chat_id) = *last_chat_id_monitor.lock().unwrap() {
+                        event = event.with("chat_id", chat_id);
                     }
-                    
-                    let _ = trigger_tx.send(TriggerEvent::new(TriggerSource::Timer));
+                    let _ = trigger_tx.send(event);
 
-                    break;
+                    // Keep counting: the switch may be paused or disarmed
+                    *last_heartbeat_monitor.lock().unwrap() = Some(Instant::now());
                 }
             }
-        });
+        };
 
 
         // Telegram bot handler
@@ -125,12 +137,12 @@ impl HeartbeatTimer {
                                 TimerCommand::Alive => {
                                     {
                                         let mut last = heartbeat.lock().unwrap();
-                                        *last = Instant::now();
+                                        *last = Some(Instant::now());
                                     }
                                     
                                     let elapsed = {
                                         let last = heartbeat.lock().unwrap();
-                                        last.elapsed().as_secs()
+                                        last.map_or(0, |t| t.elapsed().as_secs())
                                     };
                                     let remaining = timeout.saturating_sub(elapsed);
                                     let hours = remaining / 3600;
@@ -143,11 +155,12 @@ impl HeartbeatTimer {
                                     );
                                     let _ = bot.send_message(msg.chat.id, response).await;
                                     log::info!("[+] Heartbeat received - {} seconds remaining", remaining);
+                                    audit::record("heartbeat", json!({ "chat_id": msg.chat.id.0, "remaining_secs": remaining }));
                                 }
                                 TimerCommand::Status => {
                                     let elapsed = {
                                         let last = heartbeat.lock().unwrap();
-                                        last.elapsed().as_secs()
+                                        last.map_or(0, |t| t.elapsed().as_secs())
                                     };
                                     let remaining = timeout.saturating_sub(elapsed);
                                     
@@ -170,11 +183,14 @@ impl HeartbeatTimer {
                     }),
             );
 
-        Dispatcher::builder(bot, handler)
+        let mut dispatcher = Dispatcher::builder(bot, handler)
             .enable_ctrlc_handler()
-            .build()
-            .dispatch()
-            .await;
+            .build();
+
+        tokio::select! {
+            _ = countdown => {}
+            _ = dispatcher.dispatch() => {}
+        }
 
         Ok(())
     }
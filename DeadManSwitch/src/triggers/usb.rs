@@ -2,8 +2,8 @@ use rusb::{Context, UsbContext};
 use std::collections::HashSet;
 use tokio::task;
 use crate::config::Config;
-use crate::error::Result;
-use super::{TriggerEvent, TriggerSender, TriggerSource};
+use crate::error::{DmsError, Result};
+use super::{StopFlag, Trigger, TriggerContext, TriggerEvent, TriggerFuture, TriggerSource};
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct DeviceId {
@@ -11,29 +11,48 @@ struct DeviceId {
     product: u16,
 }
 
+#[derive(Clone)]
 pub struct UsbMonitor {
     config: Config,
-    trigger_tx: TriggerSender,
+}
+
+impl Trigger for UsbMonitor {
+    fn name(&self) -> &'static str {
+        "usb"
+    }
+
+    fn start(&self, ctx: TriggerContext) -> TriggerFuture {
+        Box::pin(self.clone().run(ctx))
+    }
 }
 
 impl UsbMonitor {
-    pub fn new(config: Config, trigger_tx: TriggerSender) -> Self {
-        Self { config, trigger_tx }
+    pub fn new(config: Config) -> Self {
+        Self { config }
     }
 
-    pub async fn start(self) -> Result<()> {
-        task::spawn_blocking(move || self.run()).await
-            .map_err(|_| rusb::Error::Other)?
+    async fn run(self, trigger: TriggerContext) -> Result<()> {
+        let stop = StopFlag::default();
+        let _guard = stop.guard();
+        // A panic in the poll loop is re-raised here for the supervisor to report
+        match task::spawn_blocking(move || self.poll(trigger, stop)).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Err(DmsError::Join(e.to_string())),
+        }
     }
 
-    fn run(self) -> Result<()> {
+    fn poll(self, trigger: TriggerContext, stop: StopFlag) -> Result<()> {
         let ctx = Context::new()?;
         let mut known = HashSet::new();
         
         log::warn!("[!] USB trigger: {:04x}:{:04x}", 
-                   self.config.usb_vendor_id, self.config.usb_product_id);
+                   self.config.triggers.usb.vendor_id, self.config.triggers.usb.product_id);
+        trigger.ready();
 
-        loop {
+        // Fires once per plug-in: a device must disappear before it can fire again
+        while !stop.is_set() {
+            let mut present = HashSet::new();
             for device in ctx.devices()?.iter() {
                 if let Ok(desc) = device.device_descriptor() {
                     let id = DeviceId {
@@ -41,17 +60,23 @@ impl UsbMonitor {
                         product: desc.product_id(),
                     };
                     
-                    if known.insert(id.clone()) {
-                        if id.vendor == self.config.usb_vendor_id 
-                            && id.product == self.config.usb_product_id {
-                            log::warn!("[!] USB trigger activated");
-                            let _ = self.trigger_tx.send(TriggerEvent::new(TriggerSource::Usb));
-                            return Ok(());
-                        }
+                    if !known.contains(&id)
+                        && id.vendor == self.config.triggers.usb.vendor_id 
+                        && id.product == self.config.triggers.usb.product_id {
+                        log::warn!(trigger = "usb"; "[!] USB trigger activated");
+                        let event = TriggerEvent::new(TriggerSource::Usb)
+                            .with("vendor_id", format!("{:04x}", id.vendor))
+                            .with("product_id", format!("{:04x}", id.product))
+                            .with("bus", device.bus_number())
+                            .with("address", device.address());
+                        let _ = trigger.trigger_tx.send(event);
                     }
+                    present.insert(id);
                 }
             }
+            known = present;
             std::thread::sleep(std::time::Duration::from_millis(100));  // ← CHANGED: 100ms for faster detection
         }
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1,16 +1,22 @@
 use eframe::egui;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::time::{Duration, Instant};
+use crate::actions::{ActionProgress, Activation};
+use crate::config::ActionKind;
 
 pub struct AlertWindow {
+    activation: Rc<RefCell<Activation>>,
     remaining: i32,
     flash: bool,
     last_tick: Instant,
     last_flash: Instant,
 }
 
-impl Default for AlertWindow {
-    fn default() -> Self {
+impl AlertWindow {
+    fn new(activation: Rc<RefCell<Activation>>) -> Self {
         Self {
+            activation,
             remaining: 3,
             flash: true,
             last_tick: Instant::now(),
@@ -35,6 +41,13 @@ impl eframe::App for AlertWindow {
             self.last_flash = Instant::now();
         }
 
+        // Action progress; close once the executor is done
+        let mut activation = self.activation.borrow_mut();
+        activation.poll();
+        if activation.finished.is_some() {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+
         // Request continuous repaint for animation
         ctx.request_repaint();
 
@@ -46,7 +59,18 @@ impl eframe::App for AlertWindow {
             )
             .show(ctx, |ui| {
                 ui.vertical_centered(|ui| {
-                    ui.add_space(150.0);
+                    if activation.event.test {
+                        ui.add_space(40.0);
+                        ui.label(
+                            egui::RichText::new("TEST - DRY RUN - NOTHING IS EXECUTED")
+                                .size(60.0)
+                                .color(egui::Color32::YELLOW)
+                                .strong()
+                        );
+                        ui.add_space(50.0);
+                    } else {
+                        ui.add_space(150.0);
+                    }
 
                     // Flashing title
                     let title_color = if self.flash {
@@ -64,26 +88,68 @@ impl eframe::App for AlertWindow {
 
                     ui.add_space(80.0);
 
-                    // Message
+                    // Message, from what the profile does
+                    let has = |kind: ActionKind| activation.profile.actions.iter().any(|a| a.kind == kind);
+                    let shutdown = has(ActionKind::Shutdown);
+                    let mut message = String::from("CRITICAL SECURITY ALERT\n\n");
+                    if shutdown {
+                        message.push_str("System shutdown in progress");
+                    } else {
+                        message.push_str(&format!("Running profile '{}'", activation.profile.name));
+                    }
+                    if has(ActionKind::DismountVeracrypt) || has(ActionKind::CloseLuks) {
+                        message.push_str("\nAll encrypted volumes will be dismounted");
+                    }
                     ui.label(
-                        egui::RichText::new(
-                            "CRITICAL SECURITY ALERT\n\n\
-                            System shutdown in progress\n\
-                            All encrypted volumes will be dismounted"
-                        )
-                        .size(50.0)
-                        .color(egui::Color32::WHITE)
+                        egui::RichText::new(message)
+                            .size(50.0)
+                            .color(egui::Color32::WHITE)
                     );
 
-                    ui.add_space(100.0);
+                    ui.add_space(40.0);
 
-                    // Countdown
+                    // What fired
+                    let event = &activation.event;
                     ui.label(
-                        egui::RichText::new(format!("Shutdown in: {} sec", self.remaining))
-                            .size(60.0)
-                            .color(egui::Color32::RED)
-                            .strong()
+                        egui::RichText::new(format!(
+                            "Trigger: {} on {} at {} UTC",
+                            event.source, event.hostname, event.time.format("%Y-%m-%d %H:%M:%S")
+                        ))
+                        .size(30.0)
+                        .color(egui::Color32::LIGHT_GRAY)
                     );
+                    if !event.details.is_empty() {
+                        ui.label(
+                            egui::RichText::new(event.details_line())
+                                .size(24.0)
+                                .color(egui::Color32::GRAY)
+                        );
+                    }
+
+                    ui.add_space(40.0);
+
+                    // Countdown
+                    if shutdown {
+                        ui.label(
+                            egui::RichText::new(format!("Shutdown in: {} sec", self.remaining))
+                                .size(60.0)
+                                .color(egui::Color32::RED)
+                                .strong()
+                        );
+
+                        ui.add_space(40.0);
+                    }
+
+                    // The profile's steps: the progress of those that ran,
+                    // the names of those still to come
+                    for (i, action) in activation.profile.actions.iter().enumerate() {
+                        let (text, color) = match activation.steps.get(i) {
+                            Some(ActionProgress::Step(msg)) => (format!("✔ {}", msg), egui::Color32::GREEN),
+                            Some(ActionProgress::Failed(msg)) => (format!("✘ {}", msg), egui::Color32::RED),
+                            _ => (format!("• {}", action.name()), egui::Color32::GRAY),
+                        };
+                        ui.label(egui::RichText::new(text).size(30.0).color(color));
+                    }
                 });
             });
     }
@@ -134,7 +200,9 @@ fn get_screen_size() -> (f32, f32) {
     }
 }
 
-pub fn show_alert() {
+/// Shows the fullscreen alert for `activation` until the actions are done or
+/// the window is closed.
+pub fn show_alert(activation: Rc<RefCell<Activation>>) {
     let (width, height) = get_screen_size();
 
     let options = eframe::NativeOptions {
@@ -150,6 +218,6 @@ pub fn show_alert() {
     let _ = eframe::run_native(
         "DEAD MAN SWITCH",
         options,
-        Box::new(|_cc| Box::new(AlertWindow::default())),
+        Box::new(move |_cc| Box::new(AlertWindow::new(activation))),
     );
 }
\ No newline at end of file
@@ -0,0 +1,278 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::actions::ActionExecutor;
+use crate::config::ActionConfig;
+use crate::luks;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+/// Attempts after a failed first one, unless the step sets `retries`.
+pub const DEFAULT_RETRIES: u32 = 1;
+/// Gives whatever kept a volume busy a moment to let go.
+const RETRY_PAUSE: Duration = Duration::from_secs(1);
+
+/// A mounted VeraCrypt volume.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    /// Container file or partition; empty when only mountinfo saw it
+    pub path: String,
+    /// Virtual device, e.g. `/dev/mapper/veracrypt1`
+    pub device: String,
+    pub mount_point: PathBuf,
+}
+
+impl Volume {
+    /// Whether `selector`, a volume path, mount point or device, names this volume.
+    fn matches(&self, selector: &str) -> bool {
+        !selector.is_empty()
+            && (self.path == selector || self.device == selector || self.mount_point == Path::new(selector))
+    }
+
+    fn describe(&self) -> String {
+        if self.path.is_empty() {
+            self.mount_point.display().to_string()
+        } else {
+            self.path.clone()
+        }
+    }
+}
+
+pub fn default_program() -> &'static str {
+    if cfg!(windows) {
+        "C:\\Program Files\\VeraCrypt\\VeraCrypt.exe"
+    } else if cfg!(target_os = "macos") {
+        "/Applications/VeraCrypt.app/Contents/MacOS/VeraCrypt"
+    } else {
+        "veracrypt"
+    }
+}
+
+/// The step's `program`, else the platform's default install location.
+pub fn program(action: &ActionConfig) -> &str {
+    action.program.as_deref().unwrap_or(default_program())
+}
+
+/// One command per selected volume, or a single one dismounting everything.
+pub fn dismount_commands(program: &str, volumes: &[String]) -> Vec<Vec<String>> {
+    let command = |args: &[&str]| std::iter::once(program).chain(args.iter().copied()).map(String::from).collect();
+    match (volumes.is_empty(), cfg!(windows)) {
+        (true, true) => vec![command(&["/d", "/f", "/w", "/q", "/s"])],
+        (true, false) => vec![command(&["-d", "-f"])],
+        (false, true) => volumes.iter().map(|v| command(&["/d", v, "/f", "/w", "/q", "/s"])).collect(),
+        (false, false) => volumes.iter().map(|v| command(&["-d", v, "-f"])).collect(),
+    }
+}
+
+/// Dismounts `volumes` (all of them when empty), then checks with
+/// `veracrypt -l` and, on Linux, mountinfo that they are gone. A failed
+/// attempt is retried `retries` times. Returns a summary for the step.
+pub fn dismount(program: &str, volumes: &[String], retries: u32, timeout: Duration) -> std::result::Result<String, String> {
+    let deadline = Instant::now() + timeout;
+    let remaining = || deadline.saturating_duration_since(Instant::now());
+
+    // Mountinfo alone cannot name container files, so only a listing is
+    // trusted to say what is not mounted
+    let mut pending: Vec<String> = volumes.to_vec();
+    let mut targets: Vec<String> = Vec::new();
+    if let Some((before, true)) = mounted(program, remaining()) {
+        let before = &before;
+        for selector in volumes.iter().filter(|s| !before.iter().any(|v| v.matches(s))) {
+            log::info!("[+] VeraCrypt volume {} is not mounted", selector);
+        }
+        pending.retain(|s| before.iter().any(|v| v.matches(s)));
+        targets = selected(before, volumes).iter().map(|v| v.describe()).collect();
+        if targets.is_empty() {
+            return Ok("No VeraCrypt volumes mounted".into());
+        }
+    }
+
+    let mut error = String::new();
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            log::warn!("[!] VeraCrypt dismount failed ({}), retry {}/{}", error, attempt, retries);
+            thread::sleep(RETRY_PAUSE.min(remaining()));
+        }
+        if remaining().is_zero() {
+            error = format!("timed out after {}s", timeout.as_secs());
+            break;
+        }
+
+        let mut failed = None;
+        for command in dismount_commands(program, &pending) {
+            let args: Vec<&str> = command[1..].iter().map(String::as_str).collect();
+            log::info!("[+] Running: {}", command.join(" "));
+            // Keep going: the other volumes may still dismount
+            if let Err(e) = ActionExecutor::run_command(&command[0], &args, remaining()) {
+                log::error!("[!] {} failed: {}", command.join(" "), e);
+                failed = Some(e);
+            }
+        }
+
+        let (now, listed) = mounted(program, remaining()).unwrap_or_default();
+        let still: Vec<String> = selected(&now, &pending).iter().map(|v| v.describe()).collect();
+        if still.is_empty() && !listed {
+            // Not enough to verify against; the exit status has to do
+            match failed {
+                Some(e) => error = e,
+                None => return Ok("VeraCrypt volumes dismounted".into()),
+            }
+            continue;
+        }
+        if still.is_empty() {
+            if let Some(e) = failed {
+                log::warn!("[!] VeraCrypt reported an error ({}), but no volume is left mounted", e);
+            }
+            if targets.is_empty() {
+                return Ok("VeraCrypt volumes dismounted".into());
+            }
+            return Ok(format!("VeraCrypt volumes dismounted: {}", targets.join(", ")));
+        }
+        if listed {
+            pending.retain(|s| now.iter().any(|v| v.matches(s)));
+        }
+        error = format!("still mounted: {}", still.join(", "));
+    }
+    Err(error)
+}
+
+/// The volumes `selectors` name; every volume when there are none.
+fn selected<'a>(mounted: &'a [Volume], selectors: &[String]) -> Vec<&'a Volume> {
+    mounted.iter()
+        .filter(|v| selectors.is_empty() || selectors.iter().any(|s| v.matches(s)))
+        .collect()
+}
+
+/// What is mounted according to `veracrypt -l` and, on Linux, mountinfo,
+/// and whether the listing worked. `None` when neither could tell (and
+/// always on Windows).
+fn mounted(program: &str, timeout: Duration) -> Option<(Vec<Volume>, bool)> {
+    if cfg!(windows) {
+        return None;
+    }
+    let listed = match ActionExecutor::command_output(program, &["-t", "-l", "--non-interactive"], timeout) {
+        Ok(output) => Some(parse_list(&output)),
+        Err(e) if e.contains("No volumes mounted") => Some(Vec::new()),
+        Err(e) => {
+            log::warn!("[!] Could not list VeraCrypt volumes: {}", e);
+            None
+        }
+    };
+    let in_mountinfo = if cfg!(target_os = "linux") {
+        fs::read_to_string(MOUNTINFO).ok().map(|content| parse_mountinfo(&content))
+    } else {
+        None
+    };
+    if listed.is_none() && in_mountinfo.is_none() {
+        return None;
+    }
+
+    let complete = listed.is_some();
+    let mut volumes = listed.unwrap_or_default();
+    for volume in in_mountinfo.into_iter().flatten() {
+        if !volumes.iter().any(|v| v.device == volume.device && v.mount_point == volume.mount_point) {
+            volumes.push(volume);
+        }
+    }
+    Some((volumes, complete))
+}
+
+/// Parses `veracrypt -t -l` lines: `1: /path/to/volume /dev/mapper/veracrypt1 /mnt/point`.
+fn parse_list(output: &str) -> Vec<Volume> {
+    output.lines()
+        .filter_map(|line| {
+            let (slot, rest) = line.trim().split_once(": ")?;
+            slot.parse::<u32>().ok()?;
+            // The volume may itself be a /dev path, so look past its first character
+            let device_at = rest.get(1..)?.find(" /dev/")? + 2;
+            let (device, mount_point) = rest[device_at..].split_once(' ').unwrap_or((&rest[device_at..], ""));
+            Some(Volume {
+                path: rest[..device_at - 1].to_string(),
+                device: device.to_string(),
+                mount_point: PathBuf::from(mount_point),
+            })
+        })
+        .collect()
+}
+
+/// Mounts of VeraCrypt's device-mapper volumes in a `mountinfo` file.
+fn parse_mountinfo(content: &str) -> Vec<Volume> {
+    content.lines()
+        .filter_map(|line| {
+            let (fields, tail) = line.split_once(" - ")?;
+            let mount_point = fields.split(' ').nth(4)?;
+            let source = tail.split(' ').nth(1)?;
+            source.starts_with("/dev/mapper/veracrypt").then(|| Volume {
+                path: String::new(),
+                device: source.to_string(),
+                mount_point: PathBuf::from(luks::unescape(mount_point)),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_list() {
+        let output = "1: /home/user/secret.hc /dev/mapper/veracrypt1 /media/veracrypt1\n\
+                      2: /dev/sdb1 /dev/mapper/veracrypt2 /mnt/my data\n";
+        let volumes = parse_list(output);
+        assert_eq!(volumes.len(), 2);
+        assert_eq!(volumes[0].path, "/home/user/secret.hc");
+        assert_eq!(volumes[0].device, "/dev/mapper/veracrypt1");
+        assert_eq!(volumes[1].path, "/dev/sdb1");
+        assert_eq!(volumes[1].mount_point, PathBuf::from("/mnt/my data"));
+        assert!(volumes[1].matches("/mnt/my data/"));
+        assert!(parse_list("Error: No volumes mounted.").is_empty());
+    }
+
+    #[test]
+    fn parses_mountinfo() {
+        let content = "22 1 253:0 / / rw,relatime shared:1 - ext4 /dev/mapper/root rw\n\
+                       90 22 253:3 / /mnt/vc\\040one rw,relatime shared:40 - ext4 /dev/mapper/veracrypt1 rw\n\
+                       91 22 0:50 / /tmp/.veracrypt_aux_mnt1 rw - fuse.veracrypt veracrypt rw\n";
+        let volumes = parse_mountinfo(content);
+        assert_eq!(volumes, vec![Volume {
+            path: String::new(),
+            device: "/dev/mapper/veracrypt1".into(),
+            mount_point: PathBuf::from("/mnt/vc one"),
+        }]);
+    }
+
+    /// A stand-in `veracrypt` whose first dismount fails as busy.
+    #[cfg(unix)]
+    #[test]
+    fn retries_until_dismounted() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("dms-veracrypt-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let fake = dir.join("veracrypt");
+        fs::write(&fake, format!(r#"#!/bin/sh
+cd {}
+case "$*" in
+  *-l*) [ -e mounted ] && echo "1: /vol.hc /dev/mapper/veracrypt1 /mnt/vc" && exit 0
+        echo "Error: No volumes mounted." >&2; exit 1 ;;
+  *-d*) [ -e attempted ] && rm -f mounted && exit 0
+        touch attempted; echo "Error: device is busy" >&2; exit 1 ;;
+esac
+"#, dir.display())).unwrap();
+        fs::set_permissions(&fake, fs::Permissions::from_mode(0o755)).unwrap();
+        let program = fake.to_str().unwrap();
+        let timeout = Duration::from_secs(10);
+
+        fs::write(dir.join("mounted"), "").unwrap();
+        assert_eq!(dismount(program, &["/mnt/vc".into()], 0, timeout),
+                   Err("still mounted: /vol.hc".into()));
+        fs::remove_file(dir.join("attempted")).unwrap();
+        assert_eq!(dismount(program, &["/mnt/vc".into()], 1, timeout),
+                   Ok("VeraCrypt volumes dismounted: /vol.hc".into()));
+        assert_eq!(dismount(program, &[], 1, timeout), Ok("No VeraCrypt volumes mounted".into()));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
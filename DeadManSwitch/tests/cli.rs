@@ -0,0 +1,62 @@
+//! Runs the binary with an explicit environment: the `DMS_*` overrides come
+//! from the process environment, which tests in one process would share.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The config `--print-config` shows for `file`, `env` and `args`.
+fn printed(file: &PathBuf, env: &[(&str, &str)], args: &[&str]) -> toml::Table {
+    let output = Command::new(env!("CARGO_BIN_EXE_DeadManSwitch"))
+        .env_clear()
+        .envs(env.iter().copied())
+        .arg("--config").arg(file)
+        .args(args)
+        .arg("--print-config")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // The log shares stdout
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let config: String = stdout.lines()
+        .filter(|line| !line.contains(" [+] ") && !line.contains(" [!] "))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    config.parse().unwrap()
+}
+
+#[test]
+fn cli_over_env_over_file() {
+    let dir = std::env::temp_dir().join(format!("dms-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("config.toml");
+    std::fs::write(&file, "[triggers.network]\nport = 1000\nmessage = \"from file\"\n\
+                           [triggers.flic]\nenabled = false\n[arming]\ngrace_period = 5\n").unwrap();
+    let get = |config: &toml::Table, path: &str| path.split('.')
+        .try_fold(&toml::Value::Table(config.clone()), |value, key| value.get(key))
+        .cloned()
+        .unwrap_or_else(|| panic!("{} missing", path));
+
+    let config = printed(&file, &[], &[]);
+    assert_eq!(get(&config, "triggers.network.port").as_integer(), Some(1000));
+    assert_eq!(get(&config, "logging.level").as_str(), Some("info"));
+    assert_eq!(get(&config, "audit.enabled").as_bool(), Some(true));
+
+    let env = [("DMS_NETWORK_PORT", "2000"), ("DMS_LOG_LEVEL", "debug"), ("DMS_USB_ENABLED", "1"),
+               ("DMS_AUDIT_ENABLED", "false"), ("DMS_BACKOFF", "4")];
+    let config = printed(&file, &env, &[]);
+    assert_eq!(get(&config, "triggers.network.port").as_integer(), Some(2000));
+    assert_eq!(get(&config, "triggers.network.message").as_str(), Some("from file"));
+    assert_eq!(get(&config, "arming.grace_period").as_integer(), Some(5));
+    assert_eq!(get(&config, "logging.level").as_str(), Some("debug"));
+    assert_eq!(get(&config, "triggers.usb.enabled").as_bool(), Some(true));
+    assert_eq!(get(&config, "audit.enabled").as_bool(), Some(false));
+    assert_eq!(get(&config, "supervisor.backoff").as_integer(), Some(4));
+
+    let config = printed(&file, &env, &["--network-port", "3000", "--usb-enabled", "false", "--grace-period", "0"]);
+    assert_eq!(get(&config, "triggers.network.port").as_integer(), Some(3000));
+    assert_eq!(get(&config, "triggers.usb.enabled").as_bool(), Some(false));
+    assert_eq!(get(&config, "arming.grace_period").as_integer(), Some(0));
+    assert_eq!(get(&config, "logging.level").as_str(), Some("debug"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,151 @@
+//! Drives `run_monitors` with a stand-in trigger, in a dry run.
+
+use dms::actions::{ActionProgress, ProgressReceiver};
+use dms::config::{Config, ConfigSource};
+use dms::switch;
+use dms::triggers::{Trigger, TriggerContext, TriggerEvent, TriggerFuture, TriggerRegistry, TriggerSource};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fires once per `notify_one`.
+struct FakeTrigger(Arc<Notify>);
+
+impl Trigger for FakeTrigger {
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+
+    fn start(&self, ctx: TriggerContext) -> TriggerFuture {
+        let fire = Arc::clone(&self.0);
+        Box::pin(async move {
+            ctx.ready();
+            loop {
+                fire.notified().await;
+                let _ = ctx.trigger_tx.send(TriggerEvent::new(TriggerSource::Other("fake")).with("n", 1));
+            }
+        })
+    }
+}
+
+/// `[triggers.fake]`
+#[derive(serde::Deserialize, Default)]
+struct FakeConfig {
+    enabled: bool,
+}
+
+/// A dry-run config whose only profile would create `marker`.
+fn config(after: &str, marker: &Path) -> Config {
+    let mut config = Config::from_toml(&format!(r#"
+        [triggers.fake]
+        enabled = true
+        [control]
+        enabled = false
+        [audit]
+        enabled = false
+        [arming]
+        grace_period = 0
+        [policy]
+        default = "probe"
+        [profiles.probe]
+        after = "{}"
+        actions = [{{ type = "exec", program = "touch", args = ["{}"] }}]
+    "#, after, marker.display())).unwrap();
+    config.dry_run = true;
+    config
+}
+
+/// A switch running with `FakeTrigger` on a runtime of its own.
+struct Running {
+    rt: tokio::runtime::Runtime,
+    fire: Arc<Notify>,
+    progress: ProgressReceiver,
+    task: tokio::task::JoinHandle<dms::error::Result<()>>,
+}
+
+fn start(config: Config) -> Running {
+    let fire = Arc::new(Notify::new());
+    let mut registry = TriggerRegistry::default();
+    let trigger = Arc::clone(&fire);
+    registry.register(
+        "fake",
+        move |c| c.triggers.section::<FakeConfig>("fake").is_ok_and(|f| f.enabled)
+            .then(|| Arc::new(FakeTrigger(Arc::clone(&trigger))) as _),
+        |old, new| old.triggers.other.get("fake") != new.triggers.other.get("fake"),
+    );
+
+    let (progress_tx, progress_rx) = mpsc::channel();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let task = rt.spawn(switch::run_monitors(registry, ConfigSource::default(), config, progress_tx));
+    Running { rt, fire, progress: progress_rx, task }
+}
+
+/// Everything reported for one activation, up to `Done`.
+fn activation(progress: &ProgressReceiver) -> Vec<ActionProgress> {
+    let mut seen = Vec::new();
+    while !matches!(seen.last(), Some(ActionProgress::Done)) {
+        seen.push(progress.recv_timeout(TIMEOUT).expect("activation did not finish"));
+    }
+    seen
+}
+
+fn assert_dry_run(seen: &[ActionProgress], marker: &Path) {
+    match seen {
+        [ActionProgress::Triggered(event, profile), ActionProgress::Step(step), ActionProgress::Done] => {
+            assert!(event.test);
+            assert_eq!(profile.name, "probe");
+            assert_eq!(event.source, TriggerSource::Other("fake"));
+            assert_eq!(step, &format!("Would run: touch {}", marker.display()));
+        }
+        other => panic!("unexpected progress: {:?}", other),
+    }
+    assert!(!marker.exists(), "dry run ran the command");
+}
+
+fn marker(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("dms-switch-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn event_runs_profile_in_dry_run_and_exits() {
+    let marker = marker("exit");
+    let switch = start(config("exit", &marker));
+
+    switch.fire.notify_one();
+    assert_dry_run(&activation(&switch.progress), &marker);
+    // An exiting profile ends the monitor loop
+    let result = switch.rt.block_on(async { tokio::time::timeout(TIMEOUT, switch.task).await });
+    assert!(matches!(result, Ok(Ok(Ok(())))), "{:?}", result);
+    switch.rt.shutdown_background();
+}
+
+#[test]
+fn rearms_after_profile_that_does_not_exit() {
+    let marker = marker("arm");
+    let switch = start(config("arm", &marker));
+
+    switch.fire.notify_one();
+    assert_dry_run(&activation(&switch.progress), &marker);
+
+    // Events are ignored until the switch has re-armed, so keep firing
+    let deadline = Instant::now() + TIMEOUT;
+    let second = loop {
+        assert!(Instant::now() < deadline, "switch did not re-arm");
+        switch.fire.notify_one();
+        match switch.progress.recv_timeout(Duration::from_millis(100)) {
+            Ok(first) => break first,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(e) => panic!("switch went away: {}", e),
+        }
+    };
+    let mut seen = vec![second];
+    seen.extend(activation(&switch.progress));
+    assert_dry_run(&seen, &marker);
+    assert!(!switch.task.is_finished());
+    switch.rt.shutdown_background();
+}